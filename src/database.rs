@@ -1,15 +1,27 @@
 use core::ffi::c_char;
+use core::fmt;
+use std::error::Error;
 use std::{fs, path::Path};
 
 mod btree;
-pub use btree::get_cell_content;
+pub mod freelist;
+pub use freelist::Freelist;
 
-mod record;
-pub use record::lift_encoded_string;
+mod integrity;
+mod mmap;
 mod page;
-pub use page::PageCells;
+pub use page::RecordCell;
+mod pager;
+pub use pager::{CacheStats, Pager};
+mod ptrmap;
+pub use ptrmap::{PtrmapEntry, PtrmapType};
+pub mod record;
+pub use record::{RecordValue, SchemaObject, TextEncoding};
+mod wal;
+mod write;
 
 use crate::io;
+use crate::sql;
 
 const HEADER_STRING_SIZE: usize = 16;
 const HEADER_RESERVED_SIZE: usize = 20;
@@ -77,7 +89,16 @@ pub struct DatabaseHeader {
     pub version_valid_for: u32,
     pub sqlite_version_number: u32,
 }
+/// The page size stored as 1, which does not fit in the 16-bit field
+const MAX_PAGE_SIZE: usize = 65536;
 impl DatabaseHeader {
+    /// The page size in bytes, with the value 1 read as 65536
+    pub const fn page_size_bytes(&self) -> usize {
+        match self.page_size {
+            1 => MAX_PAGE_SIZE,
+            page_size => page_size as usize,
+        }
+    }
     pub const fn to_be(self) -> DatabaseHeader {
         let DatabaseHeader {
             page_size,
@@ -117,67 +138,387 @@ impl DatabaseHeader {
         }
     }
 }
+const HEADER_SIZE: usize = core::mem::size_of::<DatabaseHeader>();
+const HEADER_STRING: &[u8; HEADER_STRING_SIZE] = b"SQLite format 3\0";
+/// The smallest usable size a page may be left with after its reserved bytes
+const MIN_USABLE_SIZE: usize = 480;
+/// A header field holding a value SQLite would not write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatabaseHeaderError {
+    HeaderString,
+    PageSize(u16),
+    FileFormatWriteVersion(u8),
+    FileFormatReadVersion(u8),
+    ReservedPageTailBytes(u8),
+    MaximumEmbeddedPayloadFraction(u8),
+    MinimumEmbeddedPayloadFraction(u8),
+    LeafPayloadFraction(u8),
+    FormatNumber(u32),
+    TextEncoding(u32),
+}
+impl fmt::Display for DatabaseHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseHeaderError::HeaderString => {
+                f.write_str("file is not a database: bad header string")
+            }
+            DatabaseHeaderError::PageSize(value) => f.write_fmt(format_args!(
+                "invalid page size {value}, expected a power of two between 512 and 32768, or 1"
+            )),
+            DatabaseHeaderError::FileFormatWriteVersion(value) => f.write_fmt(format_args!(
+                "invalid file format write version {value}, expected 1 or 2"
+            )),
+            DatabaseHeaderError::FileFormatReadVersion(value) => f.write_fmt(format_args!(
+                "invalid file format read version {value}, expected 1 or 2"
+            )),
+            DatabaseHeaderError::ReservedPageTailBytes(value) => f.write_fmt(format_args!(
+                "{value} reserved bytes leave less than {MIN_USABLE_SIZE} usable bytes per page"
+            )),
+            DatabaseHeaderError::MaximumEmbeddedPayloadFraction(value) => f.write_fmt(
+                format_args!("invalid maximum embedded payload fraction {value}, expected 64"),
+            ),
+            DatabaseHeaderError::MinimumEmbeddedPayloadFraction(value) => f.write_fmt(
+                format_args!("invalid minimum embedded payload fraction {value}, expected 32"),
+            ),
+            DatabaseHeaderError::LeafPayloadFraction(value) => f.write_fmt(format_args!(
+                "invalid leaf payload fraction {value}, expected 32"
+            )),
+            DatabaseHeaderError::FormatNumber(value) => f.write_fmt(format_args!(
+                "invalid schema format number {value}, expected 1 through 4"
+            )),
+            DatabaseHeaderError::TextEncoding(value) => f.write_fmt(format_args!(
+                "invalid text encoding {value}, expected 1, 2 or 3"
+            )),
+        }
+    }
+}
+impl Error for DatabaseHeaderError {}
+impl From<DatabaseHeaderError> for io::Error {
+    fn from(value: DatabaseHeaderError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+impl DatabaseHeader {
+    /// Checks every field with a constrained value, naming the first bad one
+    pub fn validate(&self) -> Result<(), DatabaseHeaderError> {
+        let header_string: [u8; HEADER_STRING_SIZE] =
+            unsafe { core::mem::transmute(self.header_string) };
+        if &header_string != HEADER_STRING {
+            return Err(DatabaseHeaderError::HeaderString);
+        }
+        if !(self.page_size == 1 || (self.page_size >= 512 && self.page_size.is_power_of_two())) {
+            return Err(DatabaseHeaderError::PageSize(self.page_size));
+        }
+        if !(1..=2).contains(&self.file_format_write_version) {
+            return Err(DatabaseHeaderError::FileFormatWriteVersion(
+                self.file_format_write_version,
+            ));
+        }
+        if !(1..=2).contains(&self.file_format_read_version) {
+            return Err(DatabaseHeaderError::FileFormatReadVersion(
+                self.file_format_read_version,
+            ));
+        }
+        if self.page_size_bytes() - (self.reserved_page_tail_bytes as usize) < MIN_USABLE_SIZE {
+            return Err(DatabaseHeaderError::ReservedPageTailBytes(
+                self.reserved_page_tail_bytes,
+            ));
+        }
+        if self.maximum_embedded_payload_fraction != 64 {
+            return Err(DatabaseHeaderError::MaximumEmbeddedPayloadFraction(
+                self.maximum_embedded_payload_fraction,
+            ));
+        }
+        if self.minimum_embedded_payload_fraction != 32 {
+            return Err(DatabaseHeaderError::MinimumEmbeddedPayloadFraction(
+                self.minimum_embedded_payload_fraction,
+            ));
+        }
+        if self.leaf_payload_fraction != 32 {
+            return Err(DatabaseHeaderError::LeafPayloadFraction(
+                self.leaf_payload_fraction,
+            ));
+        }
+        // Both are zero until the first schema object is created
+        let least = if self.cookie == 0 { 0 } else { 1 };
+        if !(least..=4).contains(&self.format_number) {
+            return Err(DatabaseHeaderError::FormatNumber(self.format_number));
+        }
+        if !(least..=3).contains(&self.text_encoding) {
+            return Err(DatabaseHeaderError::TextEncoding(self.text_encoding));
+        }
+        Ok(())
+    }
+    /// Writes the header back to the bytes it is stored as
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        unsafe { core::mem::transmute(self.to_be()) }
+    }
+}
+/// Reads and validates the header at the start of a database file
 fn read_header<R: io::Read>(r: &mut R) -> io::Result<DatabaseHeader> {
-    let mut buf = [0; 100];
-    io::Read::read_exact(r, &mut buf)?;
+    let buf: [u8; HEADER_SIZE] = io::read_exact_array(r)?;
     let header: DatabaseHeader = unsafe { core::mem::transmute(buf) };
-    Ok(header.to_be())
+    let header = header.to_be();
+    header.validate()?;
+    Ok(header)
 }
 #[derive(Debug)]
-pub struct Database {
+pub struct Database<R = fs::File> {
     pub header: DatabaseHeader,
     pub schema_cells: Vec<record::SchemaRecord>,
-    pub record_cells: Vec<Vec<record::SerializedRecord>>,
-}
-// fn pages_to_database(pages: Pages<btree::BTreePage>) -> Database {
-//     fn serialize_row(row: Vec<btree::BTreeCell>) -> Vec<record::SerializedRecord> {
-//         row.iter()
-//             .filter_map(get_cell_content)
-//             .inspect(|bytes| eprintln!("BYTES={bytes:X?}"))
-//             .filter_map(record::RecordBytes::from_bytes)
-//             .inspect(|b @ record::RecordBytes { bytes, .. }| {
-//                 eprintln!("RECORD_BYTES={b:X?}");
-//                 eprintln!("RECORD_STRING={:?}", String::from_utf8_lossy(bytes))
-//             })
-//             .map(record::SerializedRecord::from_bytes)
-//             .collect()
-//     }
-//     let PageCells {
-//         database_header,
-//         schema_cells,
-//         btree_cells,
-//     } = page::cells(pages);
-//     Database {
-//         header: database_header,
-//         schema_cells,
-//         record_cells: btree_cells.into_iter().map(serialize_row).collect(),
-//     }
-// }
-pub fn open(database_path: impl AsRef<Path>) -> io::Result<Database> {
-    eprintln!("OPENING DATABASE");
-    fn serialize_row(row: Vec<btree::BTreeCell>) -> Vec<record::SerializedRecord> {
-        row.iter()
-            .filter_map(get_cell_content)
-            // .inspect(|bytes| eprintln!("BYTES={bytes:X?}"))
-            .filter_map(record::RecordBytes::from_bytes)
-            // .inspect(|b @ record::RecordBytes { bytes, .. }| {
-            //     eprintln!("RECORD_BYTES={b:X?}");
-            //     eprintln!("RECORD_STRING={:?}", String::from_utf8_lossy(bytes))
-            // })
-            .map(record::SerializedRecord::from_bytes)
-            .collect()
-    }
-    fs::File::open(database_path)
-        .and_then(|mut file| page::read_cells(&mut file))
-        .map(
-            |PageCells {
-                 database_header: header,
-                 schema_cells,
-                 btree_cells,
-             }| Database {
-                header,
-                schema_cells,
-                record_cells: btree_cells.into_iter().map(serialize_row).collect(),
+    pub pager: Pager<R>,
+    /// The encoding text is stored in, which is decoded to UTF-8 as it is read
+    pub encoding: TextEncoding,
+}
+impl<R: io::Read + io::Seek> Database<R> {
+    /// Scans the rows of the table b-tree rooted at `rootpage`, reading its pages on demand
+    pub fn rows(
+        &mut self,
+        rootpage: u32,
+    ) -> io::Result<impl Iterator<Item = io::Result<page::RecordCell>> + '_> {
+        page::read_table(&mut self.pager, rootpage, self.encoding)
+    }
+    /// Scans the entries of the index b-tree rooted at `rootpage` in key order,
+    /// which is also how WITHOUT ROWID tables are stored
+    pub fn entries(
+        &mut self,
+        rootpage: u32,
+    ) -> io::Result<impl Iterator<Item = io::Result<record::SerializedRecord>> + '_> {
+        page::read_index(&mut self.pager, rootpage, self.encoding)
+    }
+    /// Walks the freelist the header points to
+    pub fn freelist(&mut self) -> io::Result<Freelist> {
+        freelist::read_freelist(&mut self.pager, &self.header)
+    }
+    /// Verifies the whole file the way `PRAGMA integrity_check` does,
+    /// giving one line per problem found or just "ok"
+    pub fn integrity_check(&mut self) -> io::Result<Vec<String>> {
+        integrity::check(&mut self.pager, &self.header, self.encoding)
+    }
+    /// Looks up the type and parent of a page in the pointer map,
+    /// which only auto-vacuum and incremental-vacuum databases have
+    pub fn parent_of(&mut self, page_idx: u32) -> io::Result<Option<PtrmapEntry>> {
+        if !ptrmap::has_ptrmap(&self.header) {
+            return Ok(None);
+        }
+        ptrmap::read_entry(&mut self.pager, page_idx).map(Some)
+    }
+}
+impl<R: io::Read + io::Write + io::Seek> Database<R> {
+    /// Refuses to write to files whose other structures writes would have to keep up to date
+    fn check_writable(&self) -> io::Result<()> {
+        let unsupported = |what| {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("writing to {what} databases is not yet supported"),
+            ))
+        };
+        if self.header.file_format_write_version == WAL_FORMAT_VERSION {
+            return unsupported("WAL mode");
+        }
+        if ptrmap::has_ptrmap(&self.header) {
+            return unsupported("auto-vacuum");
+        }
+        if self.header.format_number < 4 {
+            return unsupported("legacy file format");
+        }
+        Ok(())
+    }
+    /// Stores the header in page 1, as it stands after a change, and writes every changed page
+    fn commit(&mut self) -> io::Result<()> {
+        self.header.file_change_counter = self.header.file_change_counter.wrapping_add(1);
+        // The database size in the header is only trusted if this matches the change counter
+        self.header.version_valid_for = self.header.file_change_counter;
+        let mut page = self.pager.page(1)?.to_vec();
+        page[..HEADER_SIZE].copy_from_slice(&self.header.to_bytes());
+        self.pager.write_page(1, page)?;
+        self.pager.commit()
+    }
+    /// Runs a change, committing it if it succeeds and forgetting all of it if not.
+    ///
+    /// There is no rollback journal: changed pages are overwritten in place, so a crash
+    /// or a failed write during the commit can leave the file with only part of the change.
+    /// A failed commit is reported as such, and what is in memory is then reread from the file.
+    fn transaction<T>(
+        &mut self,
+        change: impl FnOnce(&mut Database<R>) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.check_writable()?;
+        let header = self.header;
+        if header.version_valid_for != header.file_change_counter {
+            self.header.in_header_database_size = self.pager.file_page_count()?;
+        }
+        match change(self) {
+            Ok(value) => match self.commit() {
+                Ok(()) => Ok(value),
+                Err(e) => {
+                    self.pager.rollback();
+                    // Page 1 may or may not have made it to the file, and the header with it
+                    self.header = self
+                        .pager
+                        .page(1)
+                        .and_then(|page| read_header(&mut &page[..]))
+                        .unwrap_or(header);
+                    Err(io::Error::new(
+                        e.kind(),
+                        format!("the change may have been only partly written: {e}"),
+                    ))
+                }
             },
-        )
+            Err(e) => {
+                self.header = header;
+                self.pager.rollback();
+                Err(e)
+            }
+        }
+    }
+    /// Inserts the rows of an INSERT statement, all of them or none, giving how many there were
+    pub fn insert(&mut self, insert: &sql::SqlInsert) -> io::Result<usize> {
+        self.transaction(|database| write::insert(database, insert))
+    }
+    /// Deletes the rows a DELETE statement matches, all of them or none, giving how many there were
+    pub fn delete(&mut self, delete: &sql::SqlDelete) -> io::Result<usize> {
+        self.transaction(|database| write::delete(database, delete))
+    }
+    /// Rewrites the rows an UPDATE statement matches, all of them or none, giving how many there were
+    pub fn update(&mut self, update: &sql::SqlUpdate) -> io::Result<usize> {
+        self.transaction(|database| write::update(database, update))
+    }
+}
+#[derive(Clone, Copy, Debug)]
+pub struct OpenOptions {
+    /// How many pages to keep in the page cache
+    pub cache_pages: usize,
+    /// Whether to map the file into memory rather than read it page by page.
+    ///
+    /// Falls back to reading when the file cannot be mapped.
+    pub mmap: bool,
+    /// Whether to open the file for writing too, which rules out mapping it
+    pub writable: bool,
+}
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            cache_pages: pager::DEFAULT_CACHE_PAGES,
+            mmap: false,
+            writable: false,
+        }
+    }
+}
+/// The file format versions of a database in WAL mode
+const WAL_FORMAT_VERSION: u8 = 2;
+pub fn open_with(database_path: impl AsRef<Path>, options: OpenOptions) -> io::Result<Database> {
+    eprintln!("OPENING DATABASE");
+    let database_path = database_path.as_ref();
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(options.writable)
+        .open(database_path)?;
+    let header = read_header(&mut file)?;
+    let layout = pager::layout_of(&header);
+    let mmap = options.mmap && !options.writable;
+    let mut pager = match mmap.then(|| mmap::Mmap::map(&file)) {
+        Some(Ok(map)) => Pager::mapped(map, layout),
+        Some(Err(e)) => {
+            eprintln!("FAILED TO MAP DATABASE, FALLING BACK TO READS: {e}");
+            Pager::new(file, layout, options.cache_pages)
+        }
+        None => Pager::new(file, layout, options.cache_pages),
+    };
+    if header.file_format_read_version == WAL_FORMAT_VERSION {
+        if let Some(wal) = open_wal(database_path, layout.page_size)? {
+            pager.attach_wal(wal);
+        }
+    }
+    load(pager)
+}
+/// Opens the `-wal` file next to the database, if there is one
+fn open_wal(database_path: &Path, page_size: usize) -> io::Result<Option<wal::Wal<fs::File>>> {
+    let mut wal_path = database_path.as_os_str().to_owned();
+    wal_path.push("-wal");
+    match fs::File::open(&wal_path) {
+        Ok(file) => wal::Wal::read(file, page_size).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+fn load<R: io::Read + io::Seek>(mut pager: Pager<R>) -> io::Result<Database<R>> {
+    // The log may hold a newer page 1, and with it a newer header
+    let header = pager.page(1).and_then(|page| read_header(&mut &page[..]))?;
+    // An empty database has yet to settle on an encoding
+    let encoding = match header.text_encoding {
+        0 => TextEncoding::Utf8,
+        text_encoding => TextEncoding::try_from(text_encoding)?,
+    };
+    let schema_cells = page::read_schema(&mut pager, encoding)?;
+    Ok(Database {
+        header,
+        schema_cells,
+        pager,
+        encoding,
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_header_bytes() -> [u8; HEADER_SIZE] {
+        let mut file = fs::File::open("sample.db").unwrap();
+        io::read_exact_array(&mut file).unwrap()
+    }
+    #[test]
+    fn header_round_trips_through_bytes() {
+        let bytes = sample_header_bytes();
+        let header = read_header(&mut &bytes[..]).unwrap();
+        assert_eq!(header.to_bytes(), bytes);
+    }
+    #[test]
+    fn rejects_bad_fields_by_name() {
+        let corrupt = |offset: usize, value: u8| {
+            let mut bytes = sample_header_bytes();
+            bytes[offset] = value;
+            let header: DatabaseHeader = unsafe { core::mem::transmute(bytes) };
+            header.to_be().validate()
+        };
+        assert_eq!(corrupt(0, b's'), Err(DatabaseHeaderError::HeaderString));
+        assert_eq!(
+            corrupt(17, 0xFF),
+            Err(DatabaseHeaderError::PageSize(0x10FF))
+        );
+        assert_eq!(
+            corrupt(21, 63),
+            Err(DatabaseHeaderError::MaximumEmbeddedPayloadFraction(63))
+        );
+        assert_eq!(
+            corrupt(23, 0),
+            Err(DatabaseHeaderError::LeafPayloadFraction(0))
+        );
+        assert_eq!(corrupt(47, 5), Err(DatabaseHeaderError::FormatNumber(5)));
+    }
+    #[test]
+    fn pointer_map_gives_the_parent_of_each_page() {
+        let parent = |r#type, parent| Some(PtrmapEntry { r#type, parent });
+        let mut database = open_with("sample.db", OpenOptions::default()).unwrap();
+        assert_eq!(database.parent_of(3).unwrap(), None);
+        // Table t is rooted at page 3, with leaves 9, 5 and 6, the last
+        // holding a row spilling onto overflow pages 7 and 8
+        let mut database = open_with("auto_vacuum.db", OpenOptions::default()).unwrap();
+        let entries: Vec<Option<PtrmapEntry>> = (3..=9)
+            .map(|page_idx| database.parent_of(page_idx).unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                parent(PtrmapType::RootPage, 0),
+                parent(PtrmapType::RootPage, 0),
+                parent(PtrmapType::BTree, 3),
+                parent(PtrmapType::BTree, 3),
+                parent(PtrmapType::Overflow1, 6),
+                parent(PtrmapType::Overflow2, 7),
+                parent(PtrmapType::BTree, 3),
+            ]
+        );
+        // The pointer-map page itself has no entry
+        assert!(database.parent_of(2).is_err());
+    }
 }
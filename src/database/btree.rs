@@ -2,14 +2,12 @@ use core::fmt;
 
 use std::error::Error;
 
-use crate::database::record::{self, FromRawColumn, Record, SchemaColumn};
-
 use crate::io;
 use crate::{varint, Varint};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
-enum BTreePageType {
+pub enum BTreePageType {
     InteriorIndex = 0x02,
     InteriorTable = 0x05,
     LeafIndex = 0x0A,
@@ -58,7 +56,16 @@ pub struct BTreePageHeaderInner<Ty> {
     /// within the cell content area
     pub free_bytes_in_content_area: u8,
 }
+/// The largest cell content area offset, which is stored as zero
+const MAX_CONTENT_AREA_START: usize = 65536;
 impl<Ty> BTreePageHeaderInner<Ty> {
+    /// The offset of the cell content area, with zero read as 65536
+    pub fn content_area_offset(&self) -> usize {
+        match self.content_area_start {
+            0 => MAX_CONTENT_AREA_START,
+            content_area_start => content_area_start as usize,
+        }
+    }
     #[must_use]
     pub fn to_be(self) -> BTreePageHeaderInner<Ty>
     where
@@ -127,17 +134,13 @@ fn size_of_page_header(
         right_most_pointer,
     }: &BTreePageHeader,
 ) -> usize {
-    core::mem::size_of_val(inner)
-        + right_most_pointer
-            .is_some()
-            .then_some(core::mem::size_of::<u32>())
-            .unwrap_or_default()
+    core::mem::size_of_val(inner) + right_most_pointer.map_or(0, |elt| core::mem::size_of_val(&elt))
 }
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct BTreeCellPointer(pub u16);
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct BTreeCellPointerArray(Vec<BTreeCellPointer>);
+pub struct BTreeCellPointerArray(pub Vec<BTreeCellPointer>);
 fn read_cell_pointer_array<R: io::Read>(
     r: &mut R,
     cell_count: usize,
@@ -164,8 +167,6 @@ fn size_of_cell_pointer_array(BTreeCellPointerArray(xs): &BTreeCellPointerArray)
 struct BTreePageInner {
     header: BTreePageHeader,
     pub cell_pointers: BTreeCellPointerArray,
-    #[allow(dead_code)]
-    pub reserved_area: Vec<u8>,
 }
 fn read_page_inner<R: io::Read>(r: &mut R) -> io::Result<BTreePageInner> {
     eprintln!("READING BTREE PAGE INNER");
@@ -179,153 +180,856 @@ fn read_page_inner<R: io::Read>(r: &mut R) -> io::Result<BTreePageInner> {
         "CELL POINTERS={cell_pointers:?};CELL POINTERS SIZE={}",
         size_of_cell_pointer_array(&cell_pointers)
     );
-    // TODO: Make this actually use the DB-header to calculate and read etc etc
-    let reserved_area = vec![];
     Ok(BTreePageInner {
         header,
         cell_pointers,
-        reserved_area,
     })
 }
 #[derive(Debug)]
 pub struct BTreePage {
-    #[allow(dead_code)]
     inner: BTreePageInner,
-    content: Vec<BTreeCell>,
+    pub content: Vec<BTreeCell>,
 }
-
-pub fn read_page<R: io::Read>(r: &mut R, initial_offset: usize) -> io::Result<BTreePage> {
-    #[derive(Debug)]
-    pub struct BTreePageBytes {
-        pub inner: BTreePageInner,
-        pub content: Vec<u8>,
-    }
-    fn parse_page_bytes(
-        BTreePageBytes { inner, content }: BTreePageBytes,
-        initial_offset: usize,
-    ) -> BTreePage {
-        let BTreePageInner {
-            header,
-            cell_pointers,
-            ..
-        } = &inner;
-
-        let BTreePageHeader {
-            inner: BTreePageHeaderInner { r#type, .. },
-            ..
-        } = header;
-        let content_offset = size_of_page_header(header)
-            + size_of_cell_pointer_array(cell_pointers)
-            + initial_offset;
-        let BTreeCellPointerArray(cell_pointers) = cell_pointers;
-        let content = cell_pointers
-            .iter()
-            .filter_map(move |BTreeCellPointer(offset)| {
-                let adjusted_offset = *offset as usize - content_offset;
-                let mut src = &content[adjusted_offset..];
-                read_cell(&mut src, *r#type).ok()
-            })
-            .collect();
-        BTreePage { inner, content }
-    }
-
-    read_page_inner(r)
-        .and_then(|inner| {
-            let mut content = vec![];
-            io::Read::read_to_end(r, &mut content)?;
-            Ok(BTreePageBytes { inner, content })
-        })
-        .map(|btree_page_bytes| parse_page_bytes(btree_page_bytes, initial_offset))
+/// The page number of the right-most child, present only on interior pages
+pub fn right_most_pointer(page: &BTreePage) -> Option<u32> {
+    page.inner.header.right_most_pointer
 }
-pub fn parse_cell<C: FromRawColumn>(cell: BTreeCell) -> io::Result<RecordCell<C>> {
-    match cell {
-        BTreeCell::LeafTable(BTreeLeafTableCell {
-            rowid,
-            initial_payload,
-            ..
-        }) => {
-            record::read(&mut initial_payload.as_slice()).map(|record| RecordCell { rowid, record })
-        }
-    }
+/// The offsets of the cells on the page, in key order
+pub fn cell_pointers(page: &BTreePage) -> &BTreeCellPointerArray {
+    &page.inner.cell_pointers
 }
-pub fn read_root(root_page: BTreePage) -> impl Iterator<Item = RecordCell<SchemaColumn>> {
-    root_page
-        .content
-        .into_iter()
-        .map_while(|cell| parse_cell::<SchemaColumn>(cell).ok())
+/// The offset at which the cell content area of the page starts
+pub fn content_area_offset(page: &BTreePage) -> usize {
+    page.inner.header.inner.content_area_offset()
 }
-pub fn read_tail(
-    tail: impl IntoIterator<Item = BTreePage>,
-) -> impl Iterator<Item = Vec<BTreeCell>> {
-    tail.into_iter().map(|BTreePage { content, .. }| content)
+/// Parses the b-tree page held in `page`, whose b-tree header starts at
+/// `header_offset` (past the database header on page 1).
+///
+/// Cells are read straight from the borrowed page bytes, as cell pointers
+/// are offsets from the start of the page. Any cell that cannot be read fails the page.
+pub fn read_page(page: &[u8], header_offset: usize, usable_size: usize) -> io::Result<BTreePage> {
+    let (mut page, cells) = read_cells(page, header_offset, usable_size)?;
+    page.content = cells.into_iter().collect::<io::Result<_>>()?;
+    Ok(page)
+}
+/// Parses the b-tree page held in `page` like [`read_page`], but reads each cell
+/// on its own, so that one that cannot be read leaves the others be.
+///
+/// The cells are given apart from the page, whose own `content` is left empty.
+pub fn read_cells(
+    page: &[u8],
+    header_offset: usize,
+    usable_size: usize,
+) -> io::Result<(BTreePage, Vec<io::Result<BTreeCell>>)> {
+    let mut src = page.get(header_offset..).ok_or(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "b-tree page header lies past the end of the page",
+    ))?;
+    let inner = read_page_inner(&mut src)?;
+    let r#type = inner.header.inner.r#type;
+    // The bytes past the usable size are reserved for extensions and hold no cells
+    let usable_area = &page[..usable_size.min(page.len())];
+    let content_area = inner.header.inner.content_area_offset()..usable_area.len();
+    let BTreeCellPointerArray(cell_pointers) = &inner.cell_pointers;
+    let cells = cell_pointers
+        .iter()
+        .map(|BTreeCellPointer(offset)| {
+            let offset = *offset as usize;
+            if !content_area.contains(&offset) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("cell pointer {offset} lies outside the content area {content_area:?}"),
+                ));
+            }
+            let mut src = &usable_area[offset..];
+            read_cell(&mut src, r#type, usable_size)
+        })
+        .collect();
+    let page = BTreePage {
+        inner,
+        content: vec![],
+    };
+    Ok((page, cells))
 }
 #[derive(Debug)]
 pub struct BTreeLeafTableCell {
-    #[allow(dead_code)]
     /// A [`Varint`] which is the total number
     /// of bytes of payload, including overflow
     pub total_payload_bytes: Varint,
     /// A [`Varint`] which is the integer key, a.k.a. rowid
     pub rowid: Varint,
     /// The initial portion of the payload
-    /// that does not spill to overflow pages
+    /// that does not spill to overflow pages.
+    ///
+    /// See [`pending_overflow`] for completing it
     pub initial_payload: Vec<u8>,
     /// Integer page number for the first page
     /// of the overflow page list - omitted if
     /// all payload fits on the b-tree page
-    #[allow(dead_code)]
     pub first_overflow_page_number: Option<u32>,
 }
-fn read_leaf_table_cell<R: io::Read>(r: &mut R) -> io::Result<BTreeLeafTableCell> {
+/// The largest payload a leaf table cell keeps on its page
+fn max_local_table_payload(usable_size: usize) -> usize {
+    usable_size - 35
+}
+/// The largest payload an index cell keeps on its page
+fn max_local_index_payload(usable_size: usize) -> usize {
+    ((usable_size - 12) * 64 / 255) - 23
+}
+/// Computes how many of the `total_payload_bytes` are stored on the b-tree page
+/// itself, the remainder spilling onto the overflow page chain.
+pub fn local_payload_size(
+    total_payload_bytes: usize,
+    max_local: usize,
+    usable_size: usize,
+) -> usize {
+    if total_payload_bytes <= max_local {
+        return total_payload_bytes;
+    }
+    let min_local = ((usable_size - 12) * 32 / 255) - 23;
+    let local = min_local + ((total_payload_bytes - min_local) % (usable_size - 4));
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+/// Reads the on-page part of a payload, and the overflow page number if it spills
+fn read_local_payload<R: io::Read>(
+    r: &mut R,
+    total_payload_bytes: usize,
+    max_local: usize,
+    usable_size: usize,
+) -> io::Result<(Vec<u8>, Option<u32>)> {
+    let local = local_payload_size(total_payload_bytes, max_local, usable_size);
+    let initial_payload = io::read_exact_vec(r, local)?;
+    let first_overflow_page_number = if local < total_payload_bytes {
+        io::read_exact_array(r).map(u32::from_be_bytes).map(Some)?
+    } else {
+        None
+    };
+    Ok((initial_payload, first_overflow_page_number))
+}
+fn read_leaf_table_cell<R: io::Read>(
+    r: &mut R,
+    usable_size: usize,
+) -> io::Result<BTreeLeafTableCell> {
     let total_payload_bytes = varint::read(r)?;
     let calculated_total_payload_bytes = varint::value_of(&total_payload_bytes);
     let rowid = varint::read(r)?;
 
-    let mut initial_payload = vec![0; calculated_total_payload_bytes as usize];
-    io::Read::read_exact(r, &mut initial_payload)?;
+    let (initial_payload, first_overflow_page_number) = read_local_payload(
+        r,
+        calculated_total_payload_bytes as usize,
+        max_local_table_payload(usable_size),
+        usable_size,
+    )?;
 
     Ok(BTreeLeafTableCell {
         total_payload_bytes,
         rowid,
         initial_payload,
-        first_overflow_page_number: None,
+        first_overflow_page_number,
+    })
+}
+#[derive(Debug)]
+pub struct BTreeInteriorTableCell {
+    /// A big-endian number which is the left child pointer
+    pub page_number: u32,
+    /// A [`Varint`] which is the integer key.
+    ///
+    /// Every rowid in the left child is less than or equal to it
+    pub rowid: Varint,
+}
+fn read_interior_table_cell<R: io::Read>(r: &mut R) -> io::Result<BTreeInteriorTableCell> {
+    let page_number = io::read_exact_array(r).map(u32::from_be_bytes)?;
+    let rowid = varint::read(r)?;
+    Ok(BTreeInteriorTableCell { page_number, rowid })
+}
+#[derive(Debug)]
+pub struct BTreeLeafIndexCell {
+    /// A [`Varint`] which is the total number
+    /// of bytes of key payload, including overflow
+    pub total_payload_bytes: Varint,
+    /// The initial portion of the key payload
+    /// that does not spill to overflow pages
+    pub initial_payload: Vec<u8>,
+    /// Integer page number for the first page
+    /// of the overflow page list - omitted if
+    /// all payload fits on the b-tree page
+    pub first_overflow_page_number: Option<u32>,
+}
+fn read_leaf_index_cell<R: io::Read>(
+    r: &mut R,
+    usable_size: usize,
+) -> io::Result<BTreeLeafIndexCell> {
+    let total_payload_bytes = varint::read(r)?;
+    let calculated_total_payload_bytes = varint::value_of(&total_payload_bytes);
+
+    let (initial_payload, first_overflow_page_number) = read_local_payload(
+        r,
+        calculated_total_payload_bytes as usize,
+        max_local_index_payload(usable_size),
+        usable_size,
+    )?;
+
+    Ok(BTreeLeafIndexCell {
+        total_payload_bytes,
+        initial_payload,
+        first_overflow_page_number,
+    })
+}
+#[derive(Debug)]
+pub struct BTreeInteriorIndexCell {
+    /// A big-endian number which is the left child pointer
+    pub page_number: u32,
+    /// A [`Varint`] which is the total number
+    /// of bytes of key payload, including overflow
+    pub total_payload_bytes: Varint,
+    /// The initial portion of the key payload
+    /// that does not spill to overflow pages
+    pub initial_payload: Vec<u8>,
+    /// Integer page number for the first page
+    /// of the overflow page list - omitted if
+    /// all payload fits on the b-tree page
+    pub first_overflow_page_number: Option<u32>,
+}
+fn read_interior_index_cell<R: io::Read>(
+    r: &mut R,
+    usable_size: usize,
+) -> io::Result<BTreeInteriorIndexCell> {
+    let page_number = io::read_exact_array(r).map(u32::from_be_bytes)?;
+    let BTreeLeafIndexCell {
+        total_payload_bytes,
+        initial_payload,
+        first_overflow_page_number,
+    } = read_leaf_index_cell(r, usable_size)?;
+    Ok(BTreeInteriorIndexCell {
+        page_number,
+        total_payload_bytes,
+        initial_payload,
+        first_overflow_page_number,
     })
 }
 #[derive(Debug)]
 pub enum BTreeCell {
     LeafTable(BTreeLeafTableCell),
+    InteriorTable(BTreeInteriorTableCell),
+    LeafIndex(BTreeLeafIndexCell),
+    InteriorIndex(BTreeInteriorIndexCell),
 }
 pub fn get_cell_content(cell: &BTreeCell) -> Option<&[u8]> {
     match cell {
         BTreeCell::LeafTable(BTreeLeafTableCell {
             initial_payload, ..
+        })
+        | BTreeCell::LeafIndex(BTreeLeafIndexCell {
+            initial_payload, ..
+        })
+        | BTreeCell::InteriorIndex(BTreeInteriorIndexCell {
+            initial_payload, ..
         }) => Some(initial_payload.as_slice()),
-        // _ => None,
+        BTreeCell::InteriorTable(_) => None,
     }
 }
 #[allow(dead_code)]
 pub fn print_cell_rowid(cell: &BTreeCell) {
     match cell {
-        BTreeCell::LeafTable(BTreeLeafTableCell { rowid, .. }) => {
-            eprintln!("CELL_ROWID={}", varint::value_of(rowid))
+        BTreeCell::LeafTable(BTreeLeafTableCell { rowid, .. })
+        | BTreeCell::InteriorTable(BTreeInteriorTableCell { rowid, .. }) => {
+            eprintln!("CELL_ROWID={}", varint::signed_value_of(rowid))
+        }
+        BTreeCell::LeafIndex(_) | BTreeCell::InteriorIndex(_) => {
+            eprintln!("CELL_ROWID=<index key>")
+        }
+    }
+}
+fn read_cell<R: io::Read>(
+    r: &mut R,
+    r#type: BTreePageType,
+    usable_size: usize,
+) -> io::Result<BTreeCell> {
+    match r#type {
+        BTreePageType::LeafTable => read_leaf_table_cell(r, usable_size).map(BTreeCell::LeafTable),
+        BTreePageType::InteriorTable => read_interior_table_cell(r).map(BTreeCell::InteriorTable),
+        BTreePageType::LeafIndex => read_leaf_index_cell(r, usable_size).map(BTreeCell::LeafIndex),
+        BTreePageType::InteriorIndex => {
+            read_interior_index_cell(r, usable_size).map(BTreeCell::InteriorIndex)
         }
     }
 }
-fn read_cell<R: io::Read>(r: &mut R, r#type: BTreePageType) -> io::Result<BTreeCell> {
+/// The payload of a cell which has yet to be read from the overflow page chain
+pub struct PendingOverflow<'c> {
+    pub first_overflow_page_number: u32,
+    /// The number of payload bytes stored on overflow pages
+    pub remaining: usize,
+    /// Where the overflow bytes are appended once read
+    pub payload: &'c mut Vec<u8>,
+}
+/// Gets the part of a cell payload that spilled onto overflow pages, if any
+pub fn pending_overflow(cell: &mut BTreeCell) -> Option<PendingOverflow<'_>> {
+    let (total_payload_bytes, payload, first_overflow_page_number) = match cell {
+        BTreeCell::LeafTable(BTreeLeafTableCell {
+            total_payload_bytes,
+            initial_payload,
+            first_overflow_page_number,
+            ..
+        })
+        | BTreeCell::LeafIndex(BTreeLeafIndexCell {
+            total_payload_bytes,
+            initial_payload,
+            first_overflow_page_number,
+        })
+        | BTreeCell::InteriorIndex(BTreeInteriorIndexCell {
+            total_payload_bytes,
+            initial_payload,
+            first_overflow_page_number,
+            ..
+        }) => (
+            total_payload_bytes,
+            initial_payload,
+            first_overflow_page_number,
+        ),
+        BTreeCell::InteriorTable(_) => return None,
+    };
+    let remaining = (varint::value_of(total_payload_bytes) as usize).checked_sub(payload.len())?;
+    match (first_overflow_page_number, remaining) {
+        (Some(first_overflow_page_number), 1..) => Some(PendingOverflow {
+            first_overflow_page_number: *first_overflow_page_number,
+            remaining,
+            payload,
+        }),
+        _ => None,
+    }
+}
+/// The offsets of the fields of a b-tree page header
+const FIRST_FREEBLOCK_FIELD: usize = 1;
+const CELL_COUNT_FIELD: usize = 3;
+const CONTENT_AREA_START_FIELD: usize = 5;
+const FRAGMENTED_BYTES_FIELD: usize = 7;
+/// The smallest space a cell takes, so that it can become a freeblock once freed
+const MIN_CELL_SIZE: usize = 4;
+/// The most fragmented bytes SQLite lets a page gather before defragmenting it
+const MAX_FRAGMENTED_BYTES: usize = 60;
+fn get_u16(page: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([page[offset], page[offset + 1]]) as usize
+}
+/// Stores a 16-bit field, with 65536 stored as zero
+fn put_u16(page: &mut [u8], offset: usize, value: usize) {
+    page[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+}
+/// The largest payload a leaf table cell keeps on its page, of `total_payload_bytes`
+pub fn local_table_payload_size(total_payload_bytes: usize, usable_size: usize) -> usize {
+    local_payload_size(
+        total_payload_bytes,
+        max_local_table_payload(usable_size),
+        usable_size,
+    )
+}
+/// Encodes a leaf table cell. Only the local part of `payload` is kept in it,
+/// the rest being on the overflow chain at `first_overflow_page_number`.
+pub fn leaf_table_cell(
+    rowid: i64,
+    payload: &[u8],
+    first_overflow_page_number: Option<u32>,
+    usable_size: usize,
+) -> Vec<u8> {
+    let local = local_table_payload_size(payload.len(), usable_size);
+    let mut cell: Vec<u8> = varint::encode(payload.len() as u64)
+        .into_iter()
+        .chain(Varint::from(rowid))
+        .chain(payload[..local].iter().copied())
+        .collect();
+    if let Some(first_overflow_page_number) = first_overflow_page_number {
+        cell.extend(first_overflow_page_number.to_be_bytes());
+    }
+    if cell.len() < MIN_CELL_SIZE {
+        cell.resize(MIN_CELL_SIZE, 0);
+    }
+    cell
+}
+/// Encodes an interior table cell, pointing to the child holding rowids up to `rowid`
+pub fn interior_table_cell(left_child_page_number: u32, rowid: i64) -> Vec<u8> {
+    left_child_page_number
+        .to_be_bytes()
+        .into_iter()
+        .chain(Varint::from(rowid))
+        .collect()
+}
+/// The rowid key of an encoded table cell
+pub fn table_cell_rowid(cell: &[u8], r#type: BTreePageType) -> io::Result<i64> {
+    let mut src = cell;
     match r#type {
-        BTreePageType::LeafTable => read_leaf_table_cell(r).map(BTreeCell::LeafTable),
-        _ => todo!(),
+        BTreePageType::LeafTable => {
+            varint::read(&mut src)?;
+        }
+        BTreePageType::InteriorTable => {
+            src = src.get(core::mem::size_of::<u32>()..).unwrap_or_default();
+        }
+        BTreePageType::LeafIndex | BTreePageType::InteriorIndex => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "index cells have no rowid key",
+            ))
+        }
+    }
+    varint::read(&mut src).map(|rowid| varint::signed_value_of(&rowid))
+}
+/// The left child page number of an encoded interior cell
+pub fn left_child_page_number(cell: &[u8]) -> io::Result<u32> {
+    match cell {
+        [a, b, c, d, ..] => Ok(u32::from_be_bytes([*a, *b, *c, *d])),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "interior cell is too short for a child page number",
+        )),
     }
 }
+/// The size of the b-tree header of a page of the given type
+fn size_of_header(r#type: BTreePageType) -> usize {
+    match r#type {
+        BTreePageType::InteriorIndex | BTreePageType::InteriorTable => 12,
+        BTreePageType::LeafIndex | BTreePageType::LeafTable => 8,
+    }
+}
+/// The bytes left for cells and their pointers on a page of the given type
+pub fn cell_capacity(r#type: BTreePageType, header_offset: usize, usable_size: usize) -> usize {
+    usable_size - header_offset - size_of_header(r#type)
+}
+/// The bytes cells take on a page, counting those of the pointers to them
+pub fn size_of_cells(cells: &[Vec<u8>]) -> usize {
+    cells
+        .iter()
+        .map(|cell| cell.len() + core::mem::size_of::<BTreeCellPointer>())
+        .sum()
+}
+/// A b-tree page being changed in place, whose header starts at `header_offset`
 #[derive(Debug)]
-pub struct RecordCell<C> {
-    #[allow(dead_code)]
-    pub rowid: Varint,
-    pub record: Record<C>,
+pub struct PageMut<'p> {
+    pub page: &'p mut [u8],
+    pub header_offset: usize,
+    pub usable_size: usize,
 }
-#[allow(dead_code)]
-#[repr(transparent)]
-pub struct TableBTreeInteriorCell {
-    /// A big-endian number which is the left child pointer
-    pub page_number: u32,
+impl PageMut<'_> {
+    pub fn r#type(&self) -> io::Result<BTreePageType> {
+        Ok(BTreePageType::try_from(self.page[self.header_offset])?)
+    }
+    fn field(&self, field: usize) -> usize {
+        get_u16(self.page, self.header_offset + field)
+    }
+    fn set_field(&mut self, field: usize, value: usize) {
+        put_u16(self.page, self.header_offset + field, value)
+    }
+    pub fn right_most_pointer(&self) -> io::Result<Option<u32>> {
+        let offset = self.header_offset + 8;
+        Ok(match self.r#type()? {
+            BTreePageType::InteriorIndex | BTreePageType::InteriorTable => {
+                Some(u32::from_be_bytes([
+                    self.page[offset],
+                    self.page[offset + 1],
+                    self.page[offset + 2],
+                    self.page[offset + 3],
+                ]))
+            }
+            BTreePageType::LeafIndex | BTreePageType::LeafTable => None,
+        })
+    }
+    pub fn cell_count(&self) -> usize {
+        self.field(CELL_COUNT_FIELD)
+    }
+    fn content_area_start(&self) -> usize {
+        match self.field(CONTENT_AREA_START_FIELD) {
+            0 => MAX_CONTENT_AREA_START,
+            content_area_start => content_area_start,
+        }
+    }
+    fn cell_pointers_start(&self) -> io::Result<usize> {
+        Ok(self.header_offset + size_of_header(self.r#type()?))
+    }
+    fn cell_pointers_end(&self) -> io::Result<usize> {
+        Ok(self.cell_pointers_start()? + 2 * self.cell_count())
+    }
+    /// The offset of the cell with the given index, in key order
+    pub fn cell_offset(&self, cell_idx: usize) -> io::Result<usize> {
+        if cell_idx >= self.cell_count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no cell {cell_idx} among {}", self.cell_count()),
+            ));
+        }
+        Ok(get_u16(
+            self.page,
+            self.cell_pointers_start()? + 2 * cell_idx,
+        ))
+    }
+    /// The number of bytes the cell at `offset` takes, found by reading it
+    pub fn cell_size(&self, offset: usize) -> io::Result<usize> {
+        let r#type = self.r#type()?;
+        let mut src = self
+            .page
+            .get(offset..self.usable_size)
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cell offset {offset} lies past the usable size"),
+            ))?;
+        let len = src.len();
+        read_cell(&mut src, r#type, self.usable_size)?;
+        Ok((len - src.len()).max(MIN_CELL_SIZE))
+    }
+    /// The offset and size of each freeblock, in the order of the list
+    fn freeblocks(&self) -> io::Result<Vec<(usize, usize)>> {
+        let mut freeblocks = vec![];
+        let mut offset = self.field(FIRST_FREEBLOCK_FIELD);
+        while offset != 0 {
+            if offset + 4 > self.usable_size
+                || freeblocks.last().is_some_and(|(last, _)| offset <= *last)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed freeblock list at offset {offset}"),
+                ));
+            }
+            freeblocks.push((offset, get_u16(self.page, offset + 2)));
+            offset = get_u16(self.page, offset);
+        }
+        Ok(freeblocks)
+    }
+    /// The bytes a cell could use, counting those of the pointer to it
+    pub fn free_space(&self) -> io::Result<usize> {
+        let gap = self
+            .content_area_start()
+            .saturating_sub(self.cell_pointers_end()?);
+        let freeblocks: usize = self.freeblocks()?.iter().map(|(_, size)| size).sum();
+        Ok(gap + freeblocks + self.fragmented_bytes())
+    }
+    /// Moves every cell to the end of the usable area, so that
+    /// all the free space is in the gap before the cell content area
+    pub fn defragment(&mut self) -> io::Result<()> {
+        let cells = self.cells()?;
+        let (r#type, right_most_pointer) = (self.r#type()?, self.right_most_pointer()?);
+        self.rebuild(r#type, &cells, right_most_pointer).map(|_| ())
+    }
+    /// The bytes of every cell, in key order
+    pub fn cells(&self) -> io::Result<Vec<Vec<u8>>> {
+        (0..self.cell_count())
+            .map(|cell_idx| {
+                let offset = self.cell_offset(cell_idx)?;
+                let size = self.cell_size(offset)?;
+                Ok(self.page[offset..offset + size].to_vec())
+            })
+            .collect()
+    }
+    /// Lays the page out afresh as a page of the given type holding `cells`.
+    /// Gives false, leaving the page unchanged, when they do not fit.
+    pub fn rebuild(
+        &mut self,
+        r#type: BTreePageType,
+        cells: &[Vec<u8>],
+        right_most_pointer: Option<u32>,
+    ) -> io::Result<bool> {
+        if size_of_cells(cells) > cell_capacity(r#type, self.header_offset, self.usable_size) {
+            return Ok(false);
+        }
+        self.page[self.header_offset..self.usable_size].fill(0);
+        self.page[self.header_offset] = r#type as u8;
+        if let BTreePageType::InteriorIndex | BTreePageType::InteriorTable = r#type {
+            let Some(right_most_pointer) = right_most_pointer else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "interior pages need a right-most pointer",
+                ));
+            };
+            let offset = self.header_offset + 8;
+            self.page[offset..offset + 4].copy_from_slice(&right_most_pointer.to_be_bytes());
+        }
+        let cell_pointers_start = self.cell_pointers_start()?;
+        let mut content_area_start = self.usable_size;
+        for (cell_idx, cell) in cells.iter().enumerate() {
+            content_area_start -= cell.len();
+            self.page[content_area_start..content_area_start + cell.len()].copy_from_slice(cell);
+            put_u16(
+                self.page,
+                cell_pointers_start + 2 * cell_idx,
+                content_area_start,
+            );
+        }
+        self.set_field(CELL_COUNT_FIELD, cells.len());
+        self.set_field(CONTENT_AREA_START_FIELD, content_area_start);
+        Ok(true)
+    }
+    fn fragmented_bytes(&self) -> usize {
+        self.page[self.header_offset + FRAGMENTED_BYTES_FIELD] as usize
+    }
+    fn set_fragmented_bytes(&mut self, fragmented_bytes: usize) {
+        self.page[self.header_offset + FRAGMENTED_BYTES_FIELD] = fragmented_bytes as u8;
+    }
+    /// Links up the freeblocks, each of which starts with
+    /// the offset of the next one followed by its own size
+    fn set_freeblocks(&mut self, freeblocks: &[(usize, usize)]) {
+        let mut next = 0;
+        for (offset, size) in freeblocks.iter().rev() {
+            put_u16(self.page, *offset, next);
+            put_u16(self.page, offset + 2, *size);
+            next = *offset;
+        }
+        self.set_field(FIRST_FREEBLOCK_FIELD, next);
+    }
+    /// Finds room for `size` bytes of cell content in the first freeblock big enough,
+    /// or else in the gap before the cell content area, past the room for one more
+    /// cell pointer. A freeblock left with too few bytes to stay one is taken whole,
+    /// the bytes left becoming fragmented bytes.
+    fn allocate(&mut self, size: usize) -> io::Result<Option<usize>> {
+        let mut freeblocks = self.freeblocks()?;
+        if let Some(block_idx) = freeblocks.iter().position(|(_, free)| *free >= size) {
+            let (offset, free) = freeblocks[block_idx];
+            let left = free - size;
+            if left >= MIN_CELL_SIZE {
+                // The cell takes the end of the block, which keeps its place in the list
+                freeblocks[block_idx].1 = left;
+                self.set_freeblocks(&freeblocks);
+                return Ok(Some(offset + left));
+            }
+            let fragmented_bytes = self.fragmented_bytes() + left;
+            if fragmented_bytes <= MAX_FRAGMENTED_BYTES {
+                freeblocks.remove(block_idx);
+                self.set_freeblocks(&freeblocks);
+                self.set_fragmented_bytes(fragmented_bytes);
+                return Ok(Some(offset));
+            }
+        }
+        let gap_start = self.cell_pointers_end()? + core::mem::size_of::<BTreeCellPointer>();
+        let Some(offset) = self
+            .content_area_start()
+            .checked_sub(size)
+            .filter(|offset| *offset >= gap_start)
+        else {
+            return Ok(None);
+        };
+        self.set_field(CONTENT_AREA_START_FIELD, offset);
+        Ok(Some(offset))
+    }
+    /// Frees `size` bytes of cell content at `offset`, merging them with the
+    /// freeblocks either side, or with the gap when they border on it
+    fn release(&mut self, offset: usize, size: usize) -> io::Result<()> {
+        let mut freeblocks = self.freeblocks()?;
+        let block_idx = freeblocks.partition_point(|(start, _)| *start < offset);
+        freeblocks.insert(block_idx, (offset, size));
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (start, size) in freeblocks {
+            match merged.last_mut() {
+                Some((last, last_size)) if *last + *last_size == start => *last_size += size,
+                _ => merged.push((start, size)),
+            }
+        }
+        if let Some(&(start, size)) = merged.first() {
+            if start == self.content_area_start() {
+                merged.remove(0);
+                self.set_field(CONTENT_AREA_START_FIELD, start + size);
+            }
+        }
+        self.set_freeblocks(&merged);
+        Ok(())
+    }
+    /// Inserts `cell` so that it becomes the cell with index `cell_idx`, reusing
+    /// a freeblock if one is big enough and defragmenting the page if need be.
+    /// Gives false, leaving the page unchanged, when it has too little free space.
+    pub fn insert_cell(&mut self, cell_idx: usize, cell: &[u8]) -> io::Result<bool> {
+        let needed = cell.len() + core::mem::size_of::<BTreeCellPointer>();
+        if cell_idx > self.cell_count() || self.free_space()? < needed {
+            return Ok(false);
+        }
+        // The cell pointer array can only grow into the gap
+        if self.content_area_start() < self.cell_pointers_end()? + 2 {
+            self.defragment()?;
+        }
+        let offset = match self.allocate(cell.len())? {
+            Some(offset) => offset,
+            None => {
+                self.defragment()?;
+                self.allocate(cell.len())?.ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "free space on the page is miscounted",
+                ))?
+            }
+        };
+        self.page[offset..offset + cell.len()].copy_from_slice(cell);
+        let pointer = self.cell_pointers_start()? + 2 * cell_idx;
+        let cell_pointers_end = self.cell_pointers_end()?;
+        self.page
+            .copy_within(pointer..cell_pointers_end, pointer + 2);
+        put_u16(self.page, pointer, offset);
+        self.set_field(CELL_COUNT_FIELD, self.cell_count() + 1);
+        Ok(true)
+    }
+    /// Overwrites the cell with index `cell_idx` where it lies, freeing the bytes
+    /// it no longer needs. Gives false, leaving the page unchanged, when the new
+    /// cell is bigger than the old one.
+    pub fn replace_cell(&mut self, cell_idx: usize, cell: &[u8]) -> io::Result<bool> {
+        let offset = self.cell_offset(cell_idx)?;
+        let Some(left) = self.cell_size(offset)?.checked_sub(cell.len()) else {
+            return Ok(false);
+        };
+        let fragmented_bytes = self.fragmented_bytes() + left;
+        if left < MIN_CELL_SIZE && fragmented_bytes > MAX_FRAGMENTED_BYTES {
+            return Ok(false);
+        }
+        self.page[offset..offset + cell.len()].copy_from_slice(cell);
+        match left {
+            0 => {}
+            1..=3 => self.set_fragmented_bytes(fragmented_bytes),
+            _ => self.release(offset + cell.len(), left)?,
+        }
+        Ok(true)
+    }
+    /// Removes the cell with index `cell_idx`, its space becoming a freeblock
+    pub fn remove_cell(&mut self, cell_idx: usize) -> io::Result<()> {
+        let offset = self.cell_offset(cell_idx)?;
+        let size = self.cell_size(offset)?;
+        let pointer = self.cell_pointers_start()? + 2 * cell_idx;
+        let cell_pointers_end = self.cell_pointers_end()?;
+        self.page
+            .copy_within(pointer + 2..cell_pointers_end, pointer);
+        self.set_field(CELL_COUNT_FIELD, self.cell_count() - 1);
+        match self.cell_count() {
+            // An empty page keeps no fragments around
+            0 => self.defragment(),
+            _ => self.release(offset, size),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cells_before_the_reserved_area() {
+        const PAGE_SIZE: usize = 512;
+        const USABLE_SIZE: usize = PAGE_SIZE - 8;
+        // Payload of 3 bytes with rowid 7: a record of one 8-bit integer
+        let cell = [0x03, 0x07, 0x02, 0x01, 0x2A];
+        let cell_start = USABLE_SIZE - cell.len();
+        let mut page = vec![0; PAGE_SIZE];
+        page[..8].copy_from_slice(&[0x0D, 0, 0, 0, 1, 0, 0, 0]);
+        page[5..7].copy_from_slice(&(cell_start as u16).to_be_bytes());
+        page[8..10].copy_from_slice(&(cell_start as u16).to_be_bytes());
+        page[cell_start..USABLE_SIZE].copy_from_slice(&cell);
+        page[USABLE_SIZE..].fill(0xFF);
+        let read = read_page(&page, 0, USABLE_SIZE).unwrap();
+        let [BTreeCell::LeafTable(cell)] = read.content.as_slice() else {
+            panic!("expected a single leaf table cell");
+        };
+        assert_eq!(varint::value_of(&cell.rowid), 7);
+        assert_eq!(cell.initial_payload, [0x02, 0x01, 0x2A]);
+        // A cell pointer into the reserved bytes lies outside the content area
+        page[8..10].copy_from_slice(&(USABLE_SIZE as u16).to_be_bytes());
+        assert!(read_page(&page, 0, USABLE_SIZE).is_err());
+    }
+    #[test]
+    fn reads_zero_content_area_start_as_65536() {
+        const PAGE_SIZE: usize = 65536;
+        let mut page = vec![0; PAGE_SIZE];
+        page[0] = 0x0D;
+        let page = read_page(&page, 0, PAGE_SIZE).unwrap();
+        assert_eq!(page.inner.header.inner.content_area_offset(), PAGE_SIZE);
+        assert!(page.content.is_empty());
+    }
+    #[test]
+    fn cells_that_cannot_be_read_fail_the_page() {
+        const PAGE_SIZE: usize = 512;
+        let cell = [0x03, 0x07, 0x02, 0x01, 0x2A];
+        let cell_start = PAGE_SIZE - cell.len();
+        let mut page = vec![0; PAGE_SIZE];
+        page[..8].copy_from_slice(&[0x0D, 0, 0, 0, 2, 0, 0, 0]);
+        put_u16(&mut page, CONTENT_AREA_START_FIELD, cell_start);
+        put_u16(&mut page, 8, cell_start);
+        // The second cell points into the cell pointer array
+        put_u16(&mut page, 10, 4);
+        page[cell_start..].copy_from_slice(&cell);
+        assert!(read_page(&page, 0, PAGE_SIZE).is_err());
+        let (_, cells) = read_cells(&page, 0, PAGE_SIZE).unwrap();
+        assert!(matches!(
+            cells.as_slice(),
+            [Ok(BTreeCell::LeafTable(_)), Err(_)]
+        ));
+        // A truncated cell is an error too, rather than a missing row
+        put_u16(&mut page, 10, PAGE_SIZE - 1);
+        page[PAGE_SIZE - 1] = 0x83;
+        assert!(read_page(&page, 0, PAGE_SIZE).is_err());
+    }
+    #[test]
+    fn inserts_and_removes_cells_in_key_order() {
+        const PAGE_SIZE: usize = 512;
+        let mut page = vec![0; PAGE_SIZE];
+        page[0] = 0x0D;
+        put_u16(&mut page, CONTENT_AREA_START_FIELD, PAGE_SIZE);
+        let mut page_mut = PageMut {
+            page: &mut page,
+            header_offset: 0,
+            usable_size: PAGE_SIZE,
+        };
+        let payload = [0x02, 0x01, 0x2A];
+        for (cell_idx, rowid) in [(0, 3), (0, 1), (1, 2)] {
+            let cell = leaf_table_cell(rowid, &payload, None, PAGE_SIZE);
+            assert!(page_mut.insert_cell(cell_idx, &cell).unwrap());
+        }
+        page_mut.remove_cell(1).unwrap();
+        let big = leaf_table_cell(4, &[0; 600], Some(9), PAGE_SIZE);
+        // The payload spills, leaving the local part and an overflow page number
+        assert_eq!(
+            big.len(),
+            2 + 1 + local_table_payload_size(600, PAGE_SIZE) + 4
+        );
+        assert!(page_mut.insert_cell(2, &big).unwrap());
+        let filler = leaf_table_cell(5, &[0; 400], None, PAGE_SIZE);
+        assert!(!page_mut.insert_cell(3, &filler).unwrap());
+        let read = read_page(&page, 0, PAGE_SIZE).unwrap();
+        let rowids: Vec<u64> = read
+            .content
+            .iter()
+            .map(|cell| match cell {
+                BTreeCell::LeafTable(cell) => varint::value_of(&cell.rowid),
+                _ => panic!("expected leaf table cells"),
+            })
+            .collect();
+        assert_eq!(rowids, [1, 3, 4]);
+    }
+    #[test]
+    fn removed_cells_become_freeblocks_that_are_reused() {
+        const PAGE_SIZE: usize = 512;
+        let mut page = vec![0; PAGE_SIZE];
+        page[0] = 0x0D;
+        put_u16(&mut page, CONTENT_AREA_START_FIELD, PAGE_SIZE);
+        let mut page_mut = PageMut {
+            page: &mut page,
+            header_offset: 0,
+            usable_size: PAGE_SIZE,
+        };
+        for rowid in 0..4 {
+            let cell = leaf_table_cell(rowid, &[0; 40], None, PAGE_SIZE);
+            assert!(page_mut.insert_cell(rowid as usize, &cell).unwrap());
+        }
+        let free_space = page_mut.free_space().unwrap();
+        let content_area_start = page_mut.content_area_start();
+        // Cells 1 and 2 are next to each other, so their freeblocks merge
+        page_mut.remove_cell(2).unwrap();
+        page_mut.remove_cell(1).unwrap();
+        assert_eq!(page_mut.freeblocks().unwrap().len(), 1);
+        assert_eq!(page_mut.free_space().unwrap(), free_space + 2 * (42 + 2));
+        // A smaller cell takes the end of the freeblock, leaving the gap alone
+        let cell = leaf_table_cell(1, &[0; 20], None, PAGE_SIZE);
+        assert!(page_mut.insert_cell(1, &cell).unwrap());
+        assert_eq!(page_mut.content_area_start(), content_area_start);
+        assert_eq!(
+            page_mut.freeblocks().unwrap(),
+            [(content_area_start + 42, 2 * 42 - 22)]
+        );
+        // Cell 3 starts the content area, so freeing it grows the gap over the freeblock too
+        page_mut.remove_cell(2).unwrap();
+        assert_eq!(page_mut.content_area_start(), PAGE_SIZE - 42 - 22);
+        assert!(page_mut.freeblocks().unwrap().is_empty());
+        page_mut.remove_cell(0).unwrap();
+        assert_eq!(page_mut.freeblocks().unwrap(), [(PAGE_SIZE - 42, 42)]);
+        page_mut.remove_cell(0).unwrap();
+        assert_eq!(page_mut.free_space().unwrap(), PAGE_SIZE - 8);
+    }
 }
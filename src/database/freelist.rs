@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+
+use crate::database::pager::Pager;
+use crate::database::DatabaseHeader;
+use crate::io;
+
+/// A freelist trunk page, and the leaf pages it lists
+#[derive(Debug)]
+pub struct FreelistTrunk {
+    pub page_idx: u32,
+    pub leaves: Vec<u32>,
+}
+/// The pages of a database that are not in use
+#[derive(Debug, Default)]
+pub struct Freelist {
+    pub trunks: Vec<FreelistTrunk>,
+}
+impl Freelist {
+    pub fn leaf_count(&self) -> usize {
+        self.trunks.iter().map(|trunk| trunk.leaves.len()).sum()
+    }
+    /// The number of free pages, trunks included
+    pub fn page_count(&self) -> usize {
+        self.trunks.len() + self.leaf_count()
+    }
+    /// Checks the pages found against the count the header gives
+    pub fn verify_count(&self, header: &DatabaseHeader) -> io::Result<()> {
+        let expected = header.freelist_page_count as usize;
+        let found = self.page_count();
+        if expected == found {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("freelist is {found} pages long but the header expects {expected}"),
+            ))
+        }
+    }
+}
+/// Reads a trunk page, which starts with the page number of the next trunk
+/// and a count of leaf page numbers, followed by the leaf page numbers.
+fn read_trunk<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    page_idx: u32,
+) -> io::Result<(FreelistTrunk, u32)> {
+    let usable_size = pager.layout().usable_size;
+    let page = pager.page(page_idx)?;
+    let mut words = page[..usable_size]
+        .chunks_exact(core::mem::size_of::<u32>())
+        .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+    let (Some(next_trunk), Some(leaf_count)) = (words.next(), words.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("freelist trunk page {page_idx} is too small"),
+        ));
+    };
+    let leaves: Vec<u32> = words.take(leaf_count as usize).collect();
+    if leaves.len() != leaf_count as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("freelist trunk page {page_idx} claims {leaf_count} leaves"),
+        ));
+    }
+    Ok((FreelistTrunk { page_idx, leaves }, next_trunk))
+}
+/// Walks the freelist trunk pages, starting from the one the header points to
+pub fn read_freelist<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &DatabaseHeader,
+) -> io::Result<Freelist> {
+    let mut freelist = Freelist::default();
+    let mut visited = HashSet::new();
+    let mut page_idx = header.freelist_page_idx;
+    while page_idx != 0 {
+        if !visited.insert(page_idx) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("freelist trunk page {page_idx} is visited twice"),
+            ));
+        }
+        let (trunk, next_trunk) = read_trunk(pager, page_idx)?;
+        freelist.trunks.push(trunk);
+        page_idx = next_trunk;
+    }
+    Ok(freelist)
+}
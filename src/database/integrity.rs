@@ -0,0 +1,646 @@
+use core::cmp::Ordering;
+use std::collections::BTreeSet;
+
+use crate::database::btree::{
+    self, BTreeCell, BTreeCellPointer, BTreeCellPointerArray, BTreeInteriorTableCell,
+};
+use crate::database::pager::{self, Pager};
+use crate::database::ptrmap::{self, PtrmapEntry, PtrmapType};
+use crate::database::record::{self, RecordValue, TextEncoding};
+use crate::database::{freelist, page, DatabaseHeader};
+use crate::io;
+use crate::sql;
+use crate::varint;
+
+/// SQLite stops reporting after this many problems
+const MAX_ERRORS: usize = 100;
+/// The smallest cell, which bounds how near the end of the page one may start
+const MIN_CELL_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Collation {
+    #[default]
+    Binary,
+    NoCase,
+    RTrim,
+}
+/// How one column of an index is sorted
+#[derive(Clone, Copy, Debug, Default)]
+struct ColumnOrder {
+    descending: bool,
+    collation: Collation,
+}
+impl From<&sql::SqlIndexedColumn> for ColumnOrder {
+    fn from(column: &sql::SqlIndexedColumn) -> Self {
+        let collation = match column.collation.as_deref() {
+            Some("nocase") => Collation::NoCase,
+            Some("rtrim") => Collation::RTrim,
+            _ => Collation::Binary,
+        };
+        ColumnOrder {
+            descending: column.descending,
+            collation,
+        }
+    }
+}
+/// Compares one column of two keys, text having been decoded to UTF-8 from `encoding`.
+///
+/// BINARY compares text as the database stores it, so it is encoded again for that,
+/// while NOCASE and RTRIM compare it as UTF-8 whatever the database encoding.
+fn compare_column(
+    lhs: &RecordValue,
+    rhs: &RecordValue,
+    order: ColumnOrder,
+    encoding: TextEncoding,
+) -> Ordering {
+    let trim_end = |s: &[u8]| s.len() - s.iter().rev().take_while(|elt| **elt == b' ').count();
+    let encode = |s: &[u8]| encoding.encode(&String::from_utf8_lossy(s));
+    let ordering = match (order.collation, lhs, rhs) {
+        (Collation::Binary, RecordValue::EncodedString(lhs), RecordValue::EncodedString(rhs))
+            if encoding != TextEncoding::Utf8 =>
+        {
+            encode(lhs).cmp(&encode(rhs))
+        }
+        (Collation::NoCase, RecordValue::EncodedString(lhs), RecordValue::EncodedString(rhs)) => {
+            lhs.to_ascii_lowercase().cmp(&rhs.to_ascii_lowercase())
+        }
+        (Collation::RTrim, RecordValue::EncodedString(lhs), RecordValue::EncodedString(rhs)) => {
+            lhs[..trim_end(lhs)].cmp(&rhs[..trim_end(rhs)])
+        }
+        _ => record::compare(lhs, rhs),
+    };
+    if order.descending {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+/// Compares index keys column by column, the columns past `orders` ascending
+fn compare_keys(
+    lhs: &[RecordValue],
+    rhs: &[RecordValue],
+    orders: &[ColumnOrder],
+    encoding: TextEncoding,
+) -> Ordering {
+    lhs.iter()
+        .zip(rhs)
+        .enumerate()
+        .map(|(idx, (lhs, rhs))| {
+            let order = orders.get(idx).copied().unwrap_or_default();
+            compare_column(lhs, rhs, order, encoding)
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| lhs.len().cmp(&rhs.len()))
+}
+/// An index key ordered by the BINARY collation, for looking entries up
+#[derive(Debug)]
+struct EntryKey(Vec<RecordValue>);
+impl Ord for EntryKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Any consistent order finds equal keys, so text is compared as decoded
+        compare_keys(&self.0, &other.0, &[], TextEncoding::Utf8)
+    }
+}
+impl PartialOrd for EntryKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for EntryKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+impl Eq for EntryKey {}
+/// What a b-tree was found to hold
+#[derive(Debug, Default)]
+struct TreeContents {
+    /// The rows of a table, by rowid
+    rows: Vec<(i64, Vec<RecordValue>)>,
+    /// The entries of an index, or the rows of a WITHOUT ROWID table
+    entries: Vec<Vec<RecordValue>>,
+}
+struct TreeWalk<'o> {
+    root: u32,
+    orders: &'o [ColumnOrder],
+    /// The last rowid seen, which later rowids must not precede
+    last_rowid: Option<i64>,
+    contents: TreeContents,
+}
+/// An entry of the schema table
+#[derive(Debug)]
+struct SchemaEntry {
+    r#type: String,
+    name: String,
+    table_name: String,
+    rootpage: u32,
+    sql: Option<String>,
+}
+fn schema_entry(values: &[RecordValue]) -> Option<SchemaEntry> {
+    let text = |idx: usize| match values.get(idx) {
+        Some(RecordValue::EncodedString(bytes)) => {
+            Some(String::from_utf8_lossy(bytes).into_owned())
+        }
+        _ => None,
+    };
+    let rootpage = values
+        .get(3)
+        .and_then(|rootpage| record::lift_integer(rootpage.clone()).ok())
+        .and_then(|rootpage| u32::try_from(rootpage).ok())
+        .unwrap_or(0);
+    Some(SchemaEntry {
+        r#type: text(0)?,
+        name: text(1)?,
+        table_name: text(2)?,
+        rootpage,
+        sql: text(4),
+    })
+}
+struct Checker<'p, R> {
+    pager: &'p mut Pager<R>,
+    encoding: TextEncoding,
+    has_ptrmap: bool,
+    /// Whether each page has been referenced, by page number
+    used: Vec<bool>,
+    /// Problems with the pages and b-trees of the file
+    errors: Vec<String>,
+    /// Rows and index entries which do not match up
+    row_errors: Vec<String>,
+}
+impl<R: io::Read + io::Seek> Checker<'_, R> {
+    fn is_full(&self) -> bool {
+        self.errors.len() + self.row_errors.len() >= MAX_ERRORS
+    }
+    fn report(&mut self, message: String) {
+        if !self.is_full() {
+            self.errors.push(message);
+        }
+    }
+    fn report_row(&mut self, message: String) {
+        if !self.is_full() {
+            self.row_errors.push(message);
+        }
+    }
+    /// Marks a page as used, reporting pages out of range or used before
+    fn mark(&mut self, prefix: &str, page_idx: u32) -> bool {
+        match self.used.get_mut(page_idx as usize) {
+            Some(used) if page_idx != 0 => {
+                if core::mem::replace(used, true) {
+                    self.report(format!("{prefix}2nd reference to page {page_idx}"));
+                    false
+                } else {
+                    true
+                }
+            }
+            _ => {
+                self.report(format!("{prefix}invalid page number {page_idx}"));
+                false
+            }
+        }
+    }
+    fn check_ptrmap(&mut self, prefix: &str, page_idx: u32, r#type: PtrmapType, parent: u32) {
+        if !self.has_ptrmap {
+            return;
+        }
+        match ptrmap::read_entry(self.pager, page_idx) {
+            Ok(entry) if entry == (PtrmapEntry { r#type, parent }) => {}
+            Ok(entry) => self.report(format!(
+                "{prefix}Bad ptr map entry key={page_idx} expected=({},{parent}) got=({},{})",
+                r#type as u8, entry.r#type as u8, entry.parent
+            )),
+            Err(_) => self.report(format!("{prefix}Failed to read ptrmap key={page_idx}")),
+        }
+    }
+    fn check_freelist(&mut self, header: &DatabaseHeader) {
+        let freelist = match freelist::read_freelist(self.pager, header) {
+            Ok(freelist) => freelist,
+            Err(e) => return self.report(format!("Freelist: {e}")),
+        };
+        for trunk in &freelist.trunks {
+            for page_idx in core::iter::once(trunk.page_idx).chain(trunk.leaves.iter().copied()) {
+                if self.mark("Freelist: ", page_idx) {
+                    self.check_ptrmap("Freelist: ", page_idx, PtrmapType::FreePage, 0);
+                }
+            }
+        }
+        let expected = header.freelist_page_count as usize;
+        let found = freelist.page_count();
+        if found != expected {
+            self.report(format!(
+                "Freelist: size is {found} but should be {expected}"
+            ));
+        }
+    }
+    /// Follows the overflow chain of a cell on `page_idx`,
+    /// which should be just long enough for the `remaining` payload bytes
+    fn check_overflow(
+        &mut self,
+        prefix: &str,
+        page_idx: u32,
+        first_overflow_page_number: u32,
+        remaining: usize,
+    ) -> bool {
+        let per_page = self.pager.layout().usable_size - core::mem::size_of::<u32>();
+        // There is always at least one byte remaining
+        let expected = (remaining - 1) / per_page + 1;
+        let mut found = 0;
+        let (mut parent, mut overflow_idx, mut r#type) =
+            (page_idx, first_overflow_page_number, PtrmapType::Overflow1);
+        while overflow_idx != 0 {
+            if !self.mark(prefix, overflow_idx) {
+                return false;
+            }
+            self.check_ptrmap(prefix, overflow_idx, r#type, parent);
+            let next = match self.pager.page(overflow_idx) {
+                Ok(page) => u32::from_be_bytes([page[0], page[1], page[2], page[3]]),
+                Err(e) => {
+                    self.report(format!(
+                        "{prefix}unable to read overflow page {overflow_idx}: {e}"
+                    ));
+                    return false;
+                }
+            };
+            found += 1;
+            (parent, overflow_idx, r#type) = (overflow_idx, next, PtrmapType::Overflow2);
+        }
+        if found != expected {
+            self.report(format!(
+                "{prefix}overflow list length is {found} but should be {expected}"
+            ));
+            return false;
+        }
+        true
+    }
+    /// Completes the payload of a cell and decodes its record
+    fn check_payload(
+        &mut self,
+        prefix: &str,
+        page_idx: u32,
+        mut cell: BTreeCell,
+    ) -> Option<Vec<RecordValue>> {
+        if let Some(btree::PendingOverflow {
+            first_overflow_page_number,
+            remaining,
+            ..
+        }) = btree::pending_overflow(&mut cell)
+        {
+            if !self.check_overflow(prefix, page_idx, first_overflow_page_number, remaining) {
+                return None;
+            }
+            if let Err(e) = page::read_overflow(self.pager, &mut cell) {
+                self.report(format!("{prefix}{e}"));
+                return None;
+            }
+        }
+        match page::serialize_cell(Ok(cell), self.encoding) {
+            Ok(record) => Some(record.column.cells),
+            Err(e) => {
+                self.report(format!("{prefix}{e}"));
+                None
+            }
+        }
+    }
+    /// Checks a child of `parent`, whose depth must match that of its siblings
+    fn check_child(
+        &mut self,
+        walk: &mut TreeWalk,
+        prefix: &str,
+        parent: u32,
+        child: u32,
+        depth: &mut Option<usize>,
+    ) {
+        if !self.mark(prefix, child) {
+            return;
+        }
+        self.check_ptrmap(prefix, child, PtrmapType::BTree, parent);
+        let Some(child_depth) = self.check_page(walk, child) else {
+            return;
+        };
+        match *depth {
+            Some(depth) if depth != child_depth => {
+                self.report(format!("{prefix}Child page depth differs"));
+            }
+            Some(_) => {}
+            None => *depth = Some(child_depth),
+        }
+    }
+    fn push_entry(&mut self, walk: &mut TreeWalk, prefix: &str, entry: Vec<RecordValue>) {
+        if let Some(last) = walk.contents.entries.last() {
+            if compare_keys(last, &entry, walk.orders, self.encoding).is_ge() {
+                self.report(format!("{prefix}Index entry out of order"));
+            }
+        }
+        walk.contents.entries.push(entry);
+    }
+    /// Checks a b-tree page and everything below it, giving its depth
+    fn check_page(&mut self, walk: &mut TreeWalk, page_idx: u32) -> Option<usize> {
+        let root = walk.root;
+        let (page, cells) = match page::read_cells_at(self.pager, page_idx) {
+            Ok(read) => read,
+            Err(e) => {
+                self.report(format!(
+                    "Tree {root} page {page_idx}: unable to read the page: {e}"
+                ));
+                return None;
+            }
+        };
+        let usable_size = self.pager.layout().usable_size;
+        let content_area = btree::content_area_offset(&page)..=usable_size - MIN_CELL_SIZE;
+        let BTreeCellPointerArray(cell_pointers) = btree::cell_pointers(&page);
+        let right_most_pointer = btree::right_most_pointer(&page);
+        let mut depth = None;
+        for (cell_idx, (BTreeCellPointer(offset), cell)) in
+            cell_pointers.iter().zip(cells).enumerate()
+        {
+            if self.is_full() {
+                return None;
+            }
+            let prefix = format!("Tree {root} page {page_idx} cell {cell_idx}: ");
+            if !content_area.contains(&(*offset as usize)) {
+                self.report(format!(
+                    "{prefix}Offset {offset} out of range {}..{}",
+                    content_area.start(),
+                    content_area.end()
+                ));
+                continue;
+            }
+            let cell = match cell {
+                Ok(cell) => cell,
+                Err(e) => {
+                    self.report(format!("{prefix}unable to read the cell: {e}"));
+                    continue;
+                }
+            };
+            match cell {
+                BTreeCell::InteriorTable(BTreeInteriorTableCell { page_number, rowid }) => {
+                    self.check_child(walk, &prefix, page_idx, page_number, &mut depth);
+                    let rowid = varint::signed_value_of(&rowid);
+                    if walk.last_rowid.is_some_and(|last| rowid < last) {
+                        self.report(format!("{prefix}Rowid {rowid} out of order"));
+                    }
+                    walk.last_rowid = Some(rowid);
+                }
+                BTreeCell::LeafTable(ref leaf) => {
+                    let rowid = varint::signed_value_of(&leaf.rowid);
+                    if walk.last_rowid.is_some_and(|last| rowid <= last) {
+                        self.report(format!("{prefix}Rowid {rowid} out of order"));
+                    }
+                    walk.last_rowid = Some(rowid);
+                    if let Some(values) = self.check_payload(&prefix, page_idx, cell) {
+                        walk.contents.rows.push((rowid, values));
+                    }
+                }
+                BTreeCell::InteriorIndex(ref interior) => {
+                    let page_number = interior.page_number;
+                    self.check_child(walk, &prefix, page_idx, page_number, &mut depth);
+                    if let Some(entry) = self.check_payload(&prefix, page_idx, cell) {
+                        self.push_entry(walk, &prefix, entry);
+                    }
+                }
+                BTreeCell::LeafIndex(_) => {
+                    if let Some(entry) = self.check_payload(&prefix, page_idx, cell) {
+                        self.push_entry(walk, &prefix, entry);
+                    }
+                }
+            }
+        }
+        if let Some(child) = right_most_pointer {
+            let prefix = format!("Tree {root} page {page_idx} right child: ");
+            self.check_child(walk, &prefix, page_idx, child, &mut depth);
+        }
+        Some(right_most_pointer.map_or(0, |_| depth.unwrap_or_default() + 1))
+    }
+    fn check_tree(&mut self, root: u32, orders: &[ColumnOrder]) -> TreeContents {
+        let prefix = format!("Tree {root}: ");
+        if !self.mark(&prefix, root) {
+            return TreeContents::default();
+        }
+        if root != 1 {
+            self.check_ptrmap(&prefix, root, PtrmapType::RootPage, 0);
+        }
+        let mut walk = TreeWalk {
+            root,
+            orders,
+            last_rowid: None,
+            contents: TreeContents::default(),
+        };
+        self.check_page(&mut walk, root);
+        walk.contents
+    }
+}
+/// An index and what its b-tree was found to hold
+struct CheckedIndex {
+    name: String,
+    sql: Option<sql::SqlCreateIndex>,
+    entries: Vec<Vec<RecordValue>>,
+}
+/// Checks that every row of a table has an entry in each of its indexes,
+/// and that the indexes hold no more entries than the table has rows
+fn check_indexes<R: io::Read + io::Seek>(
+    checker: &mut Checker<'_, R>,
+    table_sql: Option<&sql::SqlCreateTable>,
+    table: &TreeContents,
+    indexes: Vec<CheckedIndex>,
+) {
+    let row_count = table.rows.len().max(table.entries.len());
+    let mut lookups = vec![];
+    for CheckedIndex { name, sql, entries } in indexes {
+        if !sql.as_ref().is_some_and(|sql| sql.partial) && entries.len() != row_count {
+            checker.report_row(format!("wrong # of entries in index {name}"));
+        }
+        // Only rowid tables with plain column indexes can be looked up
+        let columns: Option<Vec<(usize, bool)>> =
+            sql.as_ref().zip(table_sql).and_then(|(sql, table_sql)| {
+                sql.columns
+                    .iter()
+                    .map(|column| {
+                        table_sql.signature.get(&column.name).map(|(idx, _)| {
+                            (*idx, table_sql.rowid_alias() == Some(column.name.as_str()))
+                        })
+                    })
+                    .collect()
+            });
+        let partial = sql.is_some_and(|sql| sql.partial);
+        if let (Some(columns), false) = (columns, partial) {
+            let entries: BTreeSet<EntryKey> = entries.into_iter().map(EntryKey).collect();
+            lookups.push((name, columns, entries));
+        }
+    }
+    for (rowid, values) in &table.rows {
+        for (name, columns, entries) in &lookups {
+            let key = columns
+                .iter()
+                .map(|(idx, is_rowid_alias)| match values.get(*idx) {
+                    Some(RecordValue::Null) | None if *is_rowid_alias => {
+                        RecordValue::TwosComplement64(*rowid)
+                    }
+                    Some(value) => value.clone(),
+                    None => RecordValue::Null,
+                })
+                .chain(core::iter::once(RecordValue::TwosComplement64(*rowid)))
+                .collect();
+            if !entries.contains(&EntryKey(key)) {
+                checker.report_row(format!("row {rowid} missing from index {name}"));
+            }
+        }
+    }
+}
+/// Verifies the whole file the way `PRAGMA integrity_check` does: that every
+/// page is used exactly once, that b-tree pages are well-formed and their keys
+/// ordered, and that the indexes match their tables.
+///
+/// Gives one line per problem found, or just "ok".
+pub fn check<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &DatabaseHeader,
+    encoding: TextEncoding,
+) -> io::Result<Vec<String>> {
+    let page_count = if header.in_header_database_size != 0
+        && header.version_valid_for == header.file_change_counter
+    {
+        header.in_header_database_size
+    } else {
+        pager.file_page_count()?
+    };
+    let layout = pager.layout();
+    let mut checker = Checker {
+        pager,
+        encoding,
+        has_ptrmap: ptrmap::has_ptrmap(header),
+        used: vec![false; page_count as usize + 1],
+        errors: vec![],
+        row_errors: vec![],
+    };
+    let pending_byte_page = pager::pending_byte_page(layout) as usize;
+    if let Some(used) = checker.used.get_mut(pending_byte_page) {
+        *used = true;
+    }
+    if checker.has_ptrmap {
+        for page_idx in 2..=page_count {
+            if ptrmap::is_ptrmap_page(layout, page_idx) {
+                checker.used[page_idx as usize] = true;
+            }
+        }
+    }
+    checker.check_freelist(header);
+    let schema: Vec<SchemaEntry> = checker
+        .check_tree(1, &[])
+        .rows
+        .iter()
+        .filter_map(|(_, values)| schema_entry(values))
+        .collect();
+    let mut tables = vec![];
+    let mut indexes = vec![];
+    for entry in schema.iter().filter(|entry| entry.rootpage != 0) {
+        match entry.r#type.as_str() {
+            "table" => {
+                let sql = entry
+                    .sql
+                    .as_ref()
+                    .and_then(|sql| sql::parse(sql.bytes()).ok())
+                    .and_then(sql::lift_create_table);
+                // WITHOUT ROWID tables are ordered by their primary key
+                let orders: Vec<ColumnOrder> = sql
+                    .iter()
+                    .filter(|sql| sql.without_rowid)
+                    .flat_map(|sql| sql.primary_key.iter().map(ColumnOrder::from))
+                    .collect();
+                let contents = checker.check_tree(entry.rootpage, &orders);
+                tables.push((entry, sql, contents));
+            }
+            "index" => {
+                let sql = entry
+                    .sql
+                    .as_ref()
+                    .and_then(|sql| sql::parse(sql.bytes()).ok())
+                    .and_then(sql::lift_create_index);
+                let orders: Vec<ColumnOrder> = sql
+                    .iter()
+                    .flat_map(|sql| sql.columns.iter().map(ColumnOrder::from))
+                    .collect();
+                let entries = checker.check_tree(entry.rootpage, &orders).entries;
+                indexes.push((
+                    entry,
+                    CheckedIndex {
+                        name: entry.name.clone(),
+                        sql,
+                        entries,
+                    },
+                ));
+            }
+            _ => {}
+        }
+    }
+    for page_idx in 1..=page_count {
+        if !checker.used[page_idx as usize] {
+            checker.report(format!("Page {page_idx}: never used"));
+        }
+    }
+    for (table, sql, contents) in &tables {
+        let (table_indexes, rest) = indexes
+            .into_iter()
+            .partition(|(index, _)| index.table_name.eq_ignore_ascii_case(&table.name));
+        indexes = rest;
+        let table_indexes = table_indexes.into_iter().map(|(_, index)| index).collect();
+        check_indexes(&mut checker, sql.as_ref(), contents, table_indexes);
+    }
+    let Checker {
+        errors, row_errors, ..
+    } = checker;
+    if errors.is_empty() && row_errors.is_empty() {
+        return Ok(vec!["ok".to_string()]);
+    }
+    let banner = (!errors.is_empty()).then(|| "*** in database main ***".to_string());
+    Ok(banner.into_iter().chain(errors).chain(row_errors).collect())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{self, pager, Database};
+
+    fn open(bytes: Vec<u8>) -> Database<io::Cursor<Vec<u8>>> {
+        let header = database::read_header(&mut bytes.as_slice()).unwrap();
+        let pager = Pager::new(io::Cursor::new(bytes), pager::layout_of(&header), 16);
+        database::load(pager).unwrap()
+    }
+    #[test]
+    fn sample_database_is_ok() {
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        assert_eq!(database.integrity_check().unwrap(), ["ok"]);
+    }
+    #[test]
+    fn binary_compares_text_as_the_database_encodes_it() {
+        let text = |s: &str| RecordValue::EncodedString(s.as_bytes().to_vec());
+        let (lhs, rhs) = ([text("a")], [text("\u{ff01}")]);
+        // U+FF01 comes after 'a' in UTF-8, but its UTF-16le bytes start with 0x01
+        assert!(compare_keys(&lhs, &rhs, &[], TextEncoding::Utf8).is_lt());
+        assert!(compare_keys(&lhs, &rhs, &[], TextEncoding::Utf16le).is_gt());
+        assert!(compare_keys(&lhs, &rhs, &[], TextEncoding::Utf16be).is_lt());
+        let descending = ColumnOrder {
+            descending: true,
+            collation: Collation::Binary,
+        };
+        assert!(compare_keys(&lhs, &rhs, &[descending], TextEncoding::Utf16le).is_lt());
+        let nocase = ColumnOrder {
+            descending: false,
+            collation: Collation::NoCase,
+        };
+        assert!(compare_keys(&lhs, &rhs, &[nocase], TextEncoding::Utf16le).is_lt());
+    }
+    #[test]
+    fn reports_pages_never_used() {
+        let mut bytes = std::fs::read("sample.db").unwrap();
+        let page_size = u16::from_be_bytes([bytes[16], bytes[17]]) as usize;
+        let page_count = (bytes.len() / page_size) as u32;
+        bytes[28..32].copy_from_slice(&(page_count + 1).to_be_bytes());
+        bytes.resize(bytes.len() + page_size, 0);
+        let mut database = open(bytes);
+        assert_eq!(
+            database.integrity_check().unwrap(),
+            [
+                "*** in database main ***".to_string(),
+                format!("Page {}: never used", page_count + 1)
+            ]
+        );
+    }
+}
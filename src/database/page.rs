@@ -1,125 +1,270 @@
-use core::iter::{from_fn as iter_fn, Zip};
-
 use std::vec::IntoIter;
 
-use crate::database;
-use crate::database::btree::{self, BTreeCell, BTreePage};
-use crate::database::record::SchemaRecord;
+use crate::database::btree::{self, BTreeCell, BTreeInteriorTableCell, BTreePage};
+use crate::database::pager::Pager;
+use crate::database::record::{
+    FromRawColumn, RecordBytes, SchemaColumn, SchemaObject, SchemaRecord, SerializedRecord,
+    TextEncoding,
+};
 use crate::database::DatabaseHeader;
 use crate::io;
+use crate::varint;
 
-#[derive(Debug)]
-pub struct RootPage<T> {
-    pub database_header: DatabaseHeader,
-    pub tail: T,
+/// Reads the b-tree page with the given (1-based) page number.
+///
+/// Page 1 is special in that its b-tree header follows the database header.
+pub fn read_page_at<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    page_idx: u32,
+) -> io::Result<BTreePage> {
+    let usable_size = pager.layout().usable_size;
+    pager
+        .page(page_idx)
+        .and_then(|page| btree::read_page(&page, header_offset(page_idx), usable_size))
 }
-fn read_root_page<R: io::Read>(r: &mut R) -> io::Result<RootPage<BTreePage>> {
-    let database_header = database::read_header(r)?;
-    let tail_size = database_header.page_size as usize - core::mem::size_of_val(&database_header);
-    eprintln!("READING ROOTPAGE");
-    io::read_exact_vec(r, tail_size)
-        .and_then(|tail| {
-            btree::read_page(
-                &mut tail.as_slice(),
-                core::mem::size_of_val(&database_header),
-            )
-        })
-        .map(|tail| RootPage {
-            database_header,
-            tail,
-        })
+/// Reads the b-tree page with the given (1-based) page number,
+/// each of its cells on its own, as [`btree::read_cells`] does
+pub fn read_cells_at<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    page_idx: u32,
+) -> io::Result<(BTreePage, Vec<io::Result<BTreeCell>>)> {
+    let usable_size = pager.layout().usable_size;
+    pager
+        .page(page_idx)
+        .and_then(|page| btree::read_cells(&page, header_offset(page_idx), usable_size))
 }
-#[derive(Debug)]
-pub struct Pages<T> {
-    pub root_page: RootPage<T>,
-    pub tail: Vec<T>,
+/// The most interior pages between a root and a leaf, past which the tree is taken to loop
+pub const MAX_DEPTH: usize = 20;
+/// The offset of the b-tree header of a page, which follows the database header on page 1
+pub fn header_offset(page_idx: u32) -> usize {
+    if page_idx == 1 {
+        core::mem::size_of::<DatabaseHeader>()
+    } else {
+        0
+    }
 }
-// pub fn read<R: io::Read>(r: &mut R) -> io::Result<Pages<BTreePage>> {
-//     read_root_page(r).map(|root_page| {
-//         let page_size = root_page.database_header.page_size as usize;
-//         Pages {
-//             root_page,
-//             tail: iter_fn(|| {
-//                 io::read_exact_vec(r, page_size)
-//                     .and_then(|tail| btree::read_page(&mut tail.as_slice(), 0))
-//                     .ok()
-//             })
-//             .collect(),
-//         }
-//     })
-// }
-#[derive(Debug)]
-pub struct PageCells {
-    pub database_header: DatabaseHeader,
-    pub schema_cells: Vec<SchemaRecord>,
-    pub btree_cells: Vec<Vec<btree::BTreeCell>>,
+/// Completes the payload of `cell` by following its overflow page chain.
+///
+/// Each overflow page starts with the page number of the next one,
+/// followed by as much of the payload as fits in the usable size.
+pub fn read_overflow<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    cell: &mut BTreeCell,
+) -> io::Result<()> {
+    let Some(btree::PendingOverflow {
+        first_overflow_page_number,
+        mut remaining,
+        payload,
+    }) = btree::pending_overflow(cell)
+    else {
+        return Ok(());
+    };
+    let usable_size = pager.layout().usable_size;
+    let mut page_idx = first_overflow_page_number;
+    while remaining > 0 {
+        if page_idx == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("overflow chain ended with {remaining} payload bytes left"),
+            ));
+        }
+        let page = pager.page(page_idx)?;
+        let (next, content) = page[..usable_size].split_at(core::mem::size_of::<u32>());
+        let chunk = remaining.min(content.len());
+        payload.extend_from_slice(&content[..chunk]);
+        remaining -= chunk;
+        page_idx = u32::from_be_bytes([next[0], next[1], next[2], next[3]]);
+    }
+    Ok(())
 }
-pub fn read_cells<R: io::Read>(r: &mut R) -> io::Result<PageCells> {
-    eprintln!("READING CELLS");
-    read_root_page(r)
-        .map(|root_page| {
-            let page_size = root_page.database_header.page_size as usize;
-            Pages {
-                root_page,
-                tail: iter_fn(|| {
-                    io::read_exact_vec(r, page_size)
-                        .and_then(|tail| btree::read_page(&mut tail.as_slice(), 0))
-                        .ok()
-                })
-                .collect(),
+enum Frame {
+    Page {
+        page_idx: u32,
+        cells: IntoIter<BTreeCell>,
+        right_most_pointer: Option<u32>,
+    },
+    Entry(BTreeCell),
+}
+/// Walks a b-tree from some rootpage, yielding its leaf cells in key order
+/// with their payloads completed from any overflow pages.
+///
+/// Interior index cells are entries too, and are yielded
+/// after every entry in their left child.
+/// Only the pages on the path to the current cell are held in memory.
+pub struct BTreeCells<'p, R> {
+    pager: &'p mut Pager<R>,
+    stack: Vec<Frame>,
+}
+impl<R: io::Read + io::Seek> BTreeCells<'_, R> {
+    /// Descends into `page_idx`, which must not already be on the path from the root
+    fn push_page(&mut self, page_idx: u32) -> io::Result<()> {
+        let mut depth = 0;
+        for frame in &self.stack {
+            match frame {
+                Frame::Page {
+                    page_idx: ancestor, ..
+                } if *ancestor == page_idx => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("b-tree page {page_idx} is a child of itself"),
+                    ))
+                }
+                Frame::Page { .. } => depth += 1,
+                Frame::Entry(_) => {}
             }
-        })
-        .map(
-            |Pages {
-                 root_page:
-                     RootPage {
-                         database_header,
-                         tail: root_tail,
-                     },
-                 tail,
-             }| {
-                use btree::RecordCell;
-                PageCells {
-                    database_header,
-                    schema_cells: btree::read_root(root_tail)
-                        .map_while(|RecordCell { mut record, .. }| {
-                            record.columns.pop().map(|column| SchemaRecord {
-                                header: record.header,
-                                column,
-                            })
-                        })
-                        .collect(),
-                    btree_cells: btree::read_tail(tail).collect(),
+        }
+        if depth > MAX_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("b-tree is deeper than {MAX_DEPTH} pages at page {page_idx}"),
+            ));
+        }
+        let page = read_page_at(self.pager, page_idx)?;
+        let right_most_pointer = btree::right_most_pointer(&page);
+        self.stack.push(Frame::Page {
+            page_idx,
+            cells: page.content.into_iter(),
+            right_most_pointer,
+        });
+        Ok(())
+    }
+    fn complete(&mut self, mut cell: BTreeCell) -> io::Result<BTreeCell> {
+        read_overflow(self.pager, &mut cell)?;
+        Ok(cell)
+    }
+}
+impl<R: io::Read + io::Seek> Iterator for BTreeCells<'_, R> {
+    type Item = io::Result<BTreeCell>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Frame::Page {
+                cells,
+                right_most_pointer,
+                ..
+            } = self.stack.last_mut()?
+            else {
+                let Some(Frame::Entry(cell)) = self.stack.pop() else {
+                    unreachable!()
+                };
+                return Some(self.complete(cell));
+            };
+            let descend_to = match cells.next() {
+                Some(BTreeCell::InteriorTable(BTreeInteriorTableCell { page_number, .. })) => {
+                    Some(page_number)
                 }
-            },
-        )
+                Some(BTreeCell::InteriorIndex(cell)) => {
+                    let page_number = cell.page_number;
+                    self.stack
+                        .push(Frame::Entry(BTreeCell::InteriorIndex(cell)));
+                    Some(page_number)
+                }
+                Some(leaf) => return Some(self.complete(leaf)),
+                // The page stays on the path until its right-most child is done
+                None => match right_most_pointer.take() {
+                    Some(page_idx) => Some(page_idx),
+                    None => {
+                        self.stack.pop();
+                        None
+                    }
+                },
+            };
+            if let Some(page_idx) = descend_to {
+                if let Err(e) = self.push_page(page_idx) {
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+/// Walks the b-tree rooted at `rootpage`
+pub fn read_btree<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    rootpage: u32,
+) -> io::Result<BTreeCells<'_, R>> {
+    let mut cells = BTreeCells {
+        pager,
+        stack: vec![],
+    };
+    cells.push_page(rootpage)?;
+    Ok(cells)
+}
+/// Reads the record of a cell, with its text decoded to UTF-8
+pub fn serialize_cell(
+    cell: io::Result<BTreeCell>,
+    encoding: TextEncoding,
+) -> io::Result<SerializedRecord> {
+    cell.and_then(|cell| {
+        btree::get_cell_content(&cell)
+            .and_then(RecordBytes::from_bytes)
+            .map(SerializedRecord::from_bytes)
+            .map(|SerializedRecord { header, column }| SerializedRecord {
+                header,
+                column: column.decode_text(encoding),
+            })
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unreadable record in b-tree cell",
+            ))
+    })
 }
-// pub fn cells(Pages { root_page, tail }: Pages<BTreePage>) -> PageCells {
-//     use btree::RecordCell;
-//     // use record::{SchemaColumn, SchemaRecord};
-//     let RootPage {
-//         database_header,
-//         tail: root_tail,
-//     } = root_page;
-//     let schema_cells = btree::read_root(root_tail)
-//         .map_while(|RecordCell { rowid, mut record }| {
-//             eprintln!("ROWID={rowid:?}");
-//             record.columns.pop().map(|column| SchemaRecord {
-//                 header: record.header,
-//                 column,
-//             })
-//         })
-//         .collect();
-//     PageCells {
-//         database_header,
-//         schema_cells,
-//         btree_cells: btree::read_tail(tail).collect(),
-//     }
-// }
-impl IntoIterator for PageCells {
-    type IntoIter = Zip<IntoIter<SchemaRecord>, IntoIter<Vec<BTreeCell>>>;
-    type Item = (SchemaRecord, Vec<BTreeCell>);
-    fn into_iter(self) -> Self::IntoIter {
-        self.schema_cells.into_iter().zip(self.btree_cells)
+/// A row of a table, keyed by its rowid
+#[derive(Debug)]
+pub struct RecordCell {
+    pub rowid: i64,
+    pub record: SerializedRecord,
+}
+/// Reads the rowid and record of a table leaf cell
+fn read_record_cell(cell: io::Result<BTreeCell>, encoding: TextEncoding) -> io::Result<RecordCell> {
+    let cell = cell?;
+    let BTreeCell::LeafTable(btree::BTreeLeafTableCell { rowid, .. }) = &cell else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a table leaf cell",
+        ));
+    };
+    let rowid = varint::signed_value_of(rowid);
+    let record = serialize_cell(Ok(cell), encoding)?;
+    Ok(RecordCell { rowid, record })
+}
+/// Reads the rows of the table b-tree rooted at `rootpage`, in rowid order
+pub fn read_table<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    rootpage: u32,
+    encoding: TextEncoding,
+) -> io::Result<impl Iterator<Item = io::Result<RecordCell>> + '_> {
+    eprintln!("READING TABLE AT ROOTPAGE={rootpage}");
+    read_btree(pager, rootpage).map(|cells| cells.map(move |cell| read_record_cell(cell, encoding)))
+}
+/// Reads the entries of the index b-tree rooted at `rootpage`, in key order
+pub fn read_index<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    rootpage: u32,
+    encoding: TextEncoding,
+) -> io::Result<impl Iterator<Item = io::Result<SerializedRecord>> + '_> {
+    eprintln!("READING INDEX AT ROOTPAGE={rootpage}");
+    read_btree(pager, rootpage).map(|cells| cells.map(move |cell| serialize_cell(cell, encoding)))
+}
+/// Reads every object of the schema table, a table b-tree rooted at page 1 like any other.
+///
+/// Objects whose SQL cannot be parsed are kept as [`SchemaObject::Unreadable`],
+/// while a row that is not shaped like a schema entry at all is an error.
+pub fn read_schema<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    encoding: TextEncoding,
+) -> io::Result<Vec<SchemaRecord>> {
+    let mut schema = vec![];
+    for cell in read_table(pager, 1, encoding)? {
+        let SerializedRecord { header, column } = cell?.record;
+        let column = SchemaColumn::from_raw_column(column).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed database schema: {e}"),
+            )
+        })?;
+        if let SchemaObject::Unreadable(e) = &column.object {
+            eprintln!("UNREADABLE SCHEMA ENTRY: {e}");
+        }
+        schema.push(SchemaRecord { header, column });
     }
+    Ok(schema)
 }
@@ -0,0 +1,366 @@
+use core::ops::{Deref, Range};
+
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use crate::database::mmap::Mmap;
+use crate::database::wal::Wal;
+use crate::database::DatabaseHeader;
+use crate::io;
+
+/// The sizes every page of a database shares
+#[derive(Clone, Copy, Debug)]
+pub struct PageLayout {
+    pub page_size: usize,
+    /// The page size less the reserved bytes at the tail of each page
+    pub usable_size: usize,
+}
+pub fn layout_of(database_header: &DatabaseHeader) -> PageLayout {
+    let page_size = database_header.page_size_bytes();
+    PageLayout {
+        page_size,
+        usable_size: page_size - database_header.reserved_page_tail_bytes as usize,
+    }
+}
+/// The offset of the byte SQLite locks files at
+const PENDING_BYTE: usize = 0x40000000;
+/// The page holding the byte SQLite locks files at, which is never used
+pub fn pending_byte_page(PageLayout { page_size, .. }: PageLayout) -> u32 {
+    (PENDING_BYTE / page_size + 1) as u32
+}
+/// How often the [`Pager`] could answer from its cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+#[derive(Debug)]
+struct CacheEntry {
+    page: Rc<[u8]>,
+    last_used: u64,
+}
+/// A bounded page cache evicting the least recently used page
+#[derive(Debug)]
+struct PageCache {
+    capacity: usize,
+    entries: HashMap<u32, CacheEntry>,
+    /// Page numbers by the tick they were last used at
+    recency: BTreeMap<u64, u32>,
+    tick: u64,
+    stats: CacheStats,
+}
+impl PageCache {
+    fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+    fn get(&mut self, page_idx: u32) -> Option<Rc<[u8]>> {
+        self.tick += 1;
+        let Some(entry) = self.entries.get_mut(&page_idx) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(self.tick, page_idx);
+        entry.last_used = self.tick;
+        Some(Rc::clone(&entry.page))
+    }
+    fn insert(&mut self, page_idx: u32, page: Rc<[u8]>) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            let Some((_, evicted)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&evicted);
+        }
+        // Each entry needs a tick of its own, or it would take another's place in `recency`
+        self.tick += 1;
+        let last_used = self.tick;
+        if let Some(CacheEntry { last_used, .. }) = self
+            .entries
+            .insert(page_idx, CacheEntry { page, last_used })
+        {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(last_used, page_idx);
+    }
+    fn remove(&mut self, page_idx: u32) {
+        if let Some(CacheEntry { last_used, .. }) = self.entries.remove(&page_idx) {
+            self.recency.remove(&last_used);
+        }
+    }
+}
+/// The number of pages a [`Pager`] caches unless told otherwise
+pub const DEFAULT_CACHE_PAGES: usize = 256;
+/// Where a [`Pager`] gets its pages from
+#[derive(Debug)]
+enum PageSource<R> {
+    /// Pages are read with [`io::Read`] and kept in a page cache
+    Buffered { file: R, cache: PageCache },
+    /// Pages are borrowed straight from a read-only mapping of the file
+    Mapped(Mmap),
+}
+/// A page handed out by a [`Pager`]
+#[derive(Debug)]
+pub enum PageRef<'a> {
+    Cached(Rc<[u8]>),
+    Mapped(&'a [u8]),
+}
+impl Deref for PageRef<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        match self {
+            PageRef::Cached(page) => page,
+            PageRef::Mapped(page) => page,
+        }
+    }
+}
+/// Fetches pages of a database file on demand, keeping
+/// the most recently used ones in memory.
+#[derive(Debug)]
+pub struct Pager<R> {
+    source: PageSource<R>,
+    layout: PageLayout,
+    /// The write-ahead log, whose frames take precedence over the file
+    wal: Option<Wal<R>>,
+    /// Pages changed since the last commit, which take precedence over the log and the file
+    dirty: BTreeMap<u32, Rc<[u8]>>,
+}
+impl<R: io::Read + io::Seek> Pager<R> {
+    pub fn new(file: R, layout: PageLayout, cache_pages: usize) -> Pager<R> {
+        Pager {
+            source: PageSource::Buffered {
+                file,
+                cache: PageCache::new(cache_pages),
+            },
+            layout,
+            wal: None,
+            dirty: BTreeMap::new(),
+        }
+    }
+    /// Serves pages from a mapping of the file, which needs no page cache
+    pub fn mapped(map: Mmap, layout: PageLayout) -> Pager<R> {
+        Pager {
+            source: PageSource::Mapped(map),
+            layout,
+            wal: None,
+            dirty: BTreeMap::new(),
+        }
+    }
+    /// Serves committed pages from the write-ahead log in preference to the file
+    pub fn attach_wal(&mut self, wal: Wal<R>) {
+        self.wal = Some(wal);
+    }
+    pub fn layout(&self) -> PageLayout {
+        self.layout
+    }
+    pub fn stats(&self) -> CacheStats {
+        match &self.source {
+            PageSource::Buffered { cache, .. } => cache.stats,
+            PageSource::Mapped(_) => CacheStats::default(),
+        }
+    }
+    /// The number of whole pages in the database file, not counting the log
+    pub fn file_page_count(&mut self) -> io::Result<u32> {
+        let len = match &mut self.source {
+            PageSource::Buffered { file, .. } => io::Seek::seek(file, io::SeekFrom::End(0))?,
+            PageSource::Mapped(map) => map.len() as u64,
+        };
+        Ok((len / self.layout.page_size as u64) as u32)
+    }
+    /// Gets the raw bytes of the page with the given (1-based) page number
+    pub fn page(&mut self, page_idx: u32) -> io::Result<PageRef<'_>> {
+        if let Some(page) = self.dirty.get(&page_idx) {
+            return Ok(PageRef::Cached(Rc::clone(page)));
+        }
+        let layout = self.layout;
+        let mut from_wal = || self.wal.as_mut().and_then(|wal| wal.page(page_idx));
+        match &mut self.source {
+            PageSource::Buffered { file, cache } => {
+                if let Some(page) = cache.get(page_idx) {
+                    return Ok(PageRef::Cached(page));
+                }
+                let page: Rc<[u8]> = match from_wal() {
+                    Some(page) => page?,
+                    None => read_raw_page_at(file, layout, page_idx)?,
+                }
+                .into();
+                cache.insert(page_idx, Rc::clone(&page));
+                Ok(PageRef::Cached(page))
+            }
+            PageSource::Mapped(map) => match from_wal() {
+                Some(page) => page.map(|page| PageRef::Cached(page.into())),
+                None => page_range(layout, page_idx)
+                    .and_then(|range| {
+                        map.get(range).ok_or(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("page {page_idx} lies past the end of the file"),
+                        ))
+                    })
+                    .map(PageRef::Mapped),
+            },
+        }
+    }
+}
+impl<R: io::Read + io::Write + io::Seek> Pager<R> {
+    /// Replaces the page with the given (1-based) page number, which may lie past
+    /// the end of the file. The change is only kept in memory until [`Pager::commit`].
+    pub fn write_page(&mut self, page_idx: u32, page: Vec<u8>) -> io::Result<()> {
+        if page.len() != self.layout.page_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page {page_idx} is {} bytes long", page.len()),
+            ));
+        }
+        self.dirty.insert(page_idx, page.into());
+        Ok(())
+    }
+    /// Forgets every change since the last commit
+    pub fn rollback(&mut self) {
+        self.dirty.clear();
+    }
+    /// Writes every changed page to the file, in page order.
+    ///
+    /// Pages are overwritten in place, so a failure part way through leaves the file
+    /// with only some of them; those not yet written stay changed in memory.
+    pub fn commit(&mut self) -> io::Result<()> {
+        let PageSource::Buffered { file, cache } = &mut self.source else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot write through a read-only mapping",
+            ));
+        };
+        while let Some((page_idx, page)) = self.dirty.pop_first() {
+            let written = page_range(self.layout, page_idx).and_then(|Range { start, .. }| {
+                io::Seek::seek(file, io::SeekFrom::Start(start as u64))?;
+                io::Write::write_all(file, &page)
+            });
+            if let Err(e) = written {
+                // The page may be half written, so neither copy can be trusted to match the file
+                cache.remove(page_idx);
+                self.dirty.insert(page_idx, page);
+                return Err(e);
+            }
+            cache.insert(page_idx, page);
+        }
+        io::Write::flush(file)
+    }
+}
+/// The byte range of the page with the given (1-based) page number
+fn page_range(PageLayout { page_size, .. }: PageLayout, page_idx: u32) -> io::Result<Range<usize>> {
+    let Some(page_offset) = page_idx.checked_sub(1) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "page numbers start at 1",
+        ));
+    };
+    let start = page_offset as usize * page_size;
+    Ok(start..start + page_size)
+}
+fn read_raw_page_at<R: io::Read + io::Seek>(
+    r: &mut R,
+    layout: PageLayout,
+    page_idx: u32,
+) -> io::Result<Vec<u8>> {
+    let Range { start, end } = page_range(layout, page_idx)?;
+    io::Seek::seek(r, io::SeekFrom::Start(start as u64))?;
+    io::read_exact_vec(r, end - start)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    const LAYOUT: PageLayout = PageLayout {
+        page_size: 4,
+        usable_size: 4,
+    };
+    fn pager(cache_pages: usize) -> Pager<io::Cursor<Vec<u8>>> {
+        let file = (1..=4u8).flat_map(|page_idx| [page_idx; 4]).collect();
+        Pager::new(io::Cursor::new(file), LAYOUT, cache_pages)
+    }
+    #[test]
+    fn reads_pages_by_number() {
+        let mut pager = pager(2);
+        assert!(pager.page(3).is_ok_and(|page| *page == [3; 4]));
+        assert!(pager.page(0).is_err());
+        assert!(pager.page(5).is_err());
+    }
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut pager = pager(2);
+        for page_idx in [1, 2, 1, 3, 1, 2] {
+            pager.page(page_idx).unwrap();
+        }
+        // 2 was evicted by 3, while 1 stayed in use
+        assert_eq!(pager.stats(), CacheStats { hits: 2, misses: 4 });
+    }
+    #[test]
+    fn mapped_pages_match_buffered_pages() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db");
+        let layout = PageLayout {
+            page_size: 4096,
+            usable_size: 4096,
+        };
+        let map = fs::File::open(path)
+            .and_then(|file| Mmap::map(&file))
+            .unwrap();
+        let mut mapped: Pager<fs::File> = Pager::mapped(map, layout);
+        let mut buffered = fs::File::open(path)
+            .map(|file| Pager::new(file, layout, 0))
+            .unwrap();
+        for page_idx in 1..=4 {
+            assert_eq!(
+                *mapped.page(page_idx).unwrap(),
+                *buffered.page(page_idx).unwrap()
+            );
+        }
+        assert!(mapped.page(5).is_err());
+    }
+    #[test]
+    fn committed_pages_reach_the_file() {
+        let mut pager = pager(2);
+        pager.page(2).unwrap();
+        pager.write_page(2, vec![9; 4]).unwrap();
+        pager.write_page(5, vec![5; 4]).unwrap();
+        assert!(pager.write_page(6, vec![6; 3]).is_err());
+        // Changes are seen before they are committed
+        assert!(pager.page(2).is_ok_and(|page| *page == [9; 4]));
+        pager.commit().unwrap();
+        let PageSource::Buffered { file, .. } = &pager.source else {
+            unreachable!()
+        };
+        assert_eq!(file.get_ref()[4..8], [9; 4]);
+        assert_eq!(file.get_ref()[16..], [5; 4]);
+        assert!(pager.page(5).is_ok_and(|page| *page == [5; 4]));
+    }
+    #[test]
+    fn committing_more_pages_than_the_cache_holds_keeps_it_bounded() {
+        let mut pager = pager(2);
+        for page_idx in 1..=6 {
+            pager.write_page(page_idx, vec![page_idx as u8; 4]).unwrap();
+        }
+        pager.commit().unwrap();
+        let cached = |pager: &Pager<io::Cursor<Vec<u8>>>| match &pager.source {
+            PageSource::Buffered { cache, .. } => (cache.entries.len(), cache.recency.len()),
+            PageSource::Mapped(_) => unreachable!(),
+        };
+        assert_eq!(cached(&pager), (2, 2));
+        for page_idx in [5, 1, 6, 2, 3] {
+            assert!(pager
+                .page(page_idx)
+                .is_ok_and(|page| *page == [page_idx as u8; 4]));
+            let (entries, recency) = cached(&pager);
+            assert!(entries <= 2 && entries == recency);
+        }
+    }
+}
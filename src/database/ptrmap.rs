@@ -0,0 +1,121 @@
+use core::fmt;
+
+use std::error::Error;
+
+use crate::database::pager::{PageLayout, Pager};
+use crate::database::DatabaseHeader;
+use crate::io;
+
+/// What a page is, as recorded by its pointer-map entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum PtrmapType {
+    /// A b-tree root page, without a parent
+    RootPage = 1,
+    /// A page on the freelist, without a parent
+    FreePage = 2,
+    /// The first page of an overflow chain, whose parent is the b-tree page holding the cell
+    Overflow1 = 3,
+    /// A later page of an overflow chain, whose parent is the previous overflow page
+    Overflow2 = 4,
+    /// A non-root b-tree page, whose parent is its parent b-tree page
+    BTree = 5,
+}
+impl TryFrom<u8> for PtrmapType {
+    type Error = PtrmapTypeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PtrmapType::RootPage),
+            2 => Ok(PtrmapType::FreePage),
+            3 => Ok(PtrmapType::Overflow1),
+            4 => Ok(PtrmapType::Overflow2),
+            5 => Ok(PtrmapType::BTree),
+            other => Err(PtrmapTypeError(other)),
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PtrmapTypeError(pub u8);
+impl fmt::Display for PtrmapTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args! {"Error parsing PtrmapType from 0x{:01X}", self.0})
+    }
+}
+impl Error for PtrmapTypeError {}
+impl From<PtrmapTypeError> for io::Error {
+    fn from(value: PtrmapTypeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PtrmapEntry {
+    pub r#type: PtrmapType,
+    /// The parent page number, zero for root pages and free pages
+    pub parent: u32,
+}
+const PTRMAP_ENTRY_SIZE: usize = 5;
+/// The first pointer-map page always directly follows page 1
+const FIRST_PTRMAP_PAGE: u32 = 2;
+/// Whether the database has pointer-map pages, as in auto-vacuum and incremental-vacuum modes
+pub fn has_ptrmap(header: &DatabaseHeader) -> bool {
+    header.largest_root_page_idx != 0
+}
+/// The number of pages whose entries fit on one pointer-map page
+fn entries_per_page(PageLayout { usable_size, .. }: PageLayout) -> u32 {
+    (usable_size / PTRMAP_ENTRY_SIZE) as u32
+}
+/// The pointer-map page holding the entry of `page_idx`.
+///
+/// Each pointer-map page is followed by the pages it has entries for,
+/// after which comes the next pointer-map page.
+pub fn ptrmap_page_of(layout: PageLayout, page_idx: u32) -> u32 {
+    let stride = entries_per_page(layout) + 1;
+    FIRST_PTRMAP_PAGE + (page_idx.saturating_sub(FIRST_PTRMAP_PAGE) / stride) * stride
+}
+pub fn is_ptrmap_page(layout: PageLayout, page_idx: u32) -> bool {
+    page_idx >= FIRST_PTRMAP_PAGE && ptrmap_page_of(layout, page_idx) == page_idx
+}
+/// Looks up the pointer-map entry of `page_idx`, which gives its type and parent
+pub fn read_entry<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    page_idx: u32,
+) -> io::Result<PtrmapEntry> {
+    let layout = pager.layout();
+    if page_idx <= FIRST_PTRMAP_PAGE || is_ptrmap_page(layout, page_idx) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("page {page_idx} has no pointer-map entry"),
+        ));
+    }
+    let ptrmap_page_idx = ptrmap_page_of(layout, page_idx);
+    let offset = PTRMAP_ENTRY_SIZE * (page_idx - ptrmap_page_idx - 1) as usize;
+    let page = pager.page(ptrmap_page_idx)?;
+    let entry = &page[offset..offset + PTRMAP_ENTRY_SIZE];
+    Ok(PtrmapEntry {
+        r#type: PtrmapType::try_from(entry[0])?,
+        parent: u32::from_be_bytes([entry[1], entry[2], entry[3], entry[4]]),
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: PageLayout = PageLayout {
+        page_size: 1024,
+        usable_size: 1024,
+    };
+    #[test]
+    fn ptrmap_pages_are_evenly_spaced() {
+        // 204 entries fit on a 1024 byte page
+        let ptrmap_pages: Vec<u32> = (1..=500)
+            .filter(|page_idx| is_ptrmap_page(LAYOUT, *page_idx))
+            .collect();
+        assert_eq!(ptrmap_pages, [2, 207, 412]);
+    }
+    #[test]
+    fn pages_map_to_the_preceding_ptrmap_page() {
+        assert_eq!(ptrmap_page_of(LAYOUT, 3), 2);
+        assert_eq!(ptrmap_page_of(LAYOUT, 206), 2);
+        assert_eq!(ptrmap_page_of(LAYOUT, 208), 207);
+    }
+}
@@ -1,11 +1,15 @@
+use core::cmp::Ordering;
+use core::fmt;
+
 use crate::io;
 use crate::{varint, Varint};
 
 mod schema;
-pub use schema::{SchemaColumn, SchemaRecord};
+pub use schema::{SchemaColumn, SchemaObject, SchemaRecord};
 
 #[derive(Debug)]
 pub struct RecordHeader {
+    #[allow(dead_code)]
     pub size: Varint,
     pub serial_types: Vec<Varint>,
 }
@@ -25,54 +29,89 @@ pub fn read_header<R: io::Read>(r: &mut R) -> io::Result<RecordHeader> {
         serial_types: core::iter::from_fn(|| varint::read(&mut src).ok()).collect(),
     })
 }
-#[derive(Debug)]
-pub struct RawRecord {
-    pub header: RecordHeader,
-    pub data: Vec<u8>,
-}
-fn read_raw<R: io::Read>(r: &mut R) -> io::Result<RawRecord> {
-    let header = read_header(r)?;
-    let mut data = vec![];
-    io::Read::read_to_end(r, &mut data)?;
-    Ok(RawRecord { header, data })
-}
 // #[derive(Debug)]
 // pub struct RecordElement(pub Vec<u8>);
+const NULL_SERIAL_TYPE: u64 = 0;
+const EIGHT_BIT_SERIAL_TYPE: u64 = 1;
+const SIXTEEN_BIT_SERIAL_TYPE: u64 = 2;
+const TWENTY_FOUR_BIT_SERIAL_TYPE: u64 = 3;
+const THIRTY_TWO_BIT_SERIAL_TYPE: u64 = 4;
+const FORTY_EIGHT_BIT_SERIAL_TYPE: u64 = 5;
+const SIXTY_FOUR_BIT_SERIAL_TYPE: u64 = 6;
+const FLOAT_SERIAL_TYPE: u64 = 7;
+const ZERO_SERIAL_TYPE: u64 = 8;
+const ONE_SERIAL_TYPE: u64 = 9;
 pub fn is_string_serial_type(serial_type_value: u64) -> bool {
-    let is_even = (serial_type_value % 2) == 0;
+    let is_even = (serial_type_value & 1) == 0;
     (serial_type_value >= 13) && !is_even
 }
 pub fn string_serial_type_size(serial_type_value: u64) -> usize {
     (serial_type_value as usize - 13) / 2
 }
-#[allow(dead_code)]
-fn serial_type_size(serial_type: &Varint) -> usize {
-    const NULL_SERIAL_TYPE: u64 = 0;
-    const EIGHT_BIT_SERIAL_TYPE: u64 = 1;
+pub fn is_blob_serial_type(serial_type_value: u64) -> bool {
+    let is_even = (serial_type_value & 1) == 0;
+    (serial_type_value >= 12) && is_even
+}
+pub fn blob_serial_type_size(serial_type_value: u64) -> usize {
+    (serial_type_value as usize - 12) / 2
+}
+/// The number of body bytes a value of the given serial type occupies
+fn serial_type_size(serial_type: &Varint) -> io::Result<usize> {
     match varint::value_of(serial_type) {
-        // Value is a null
-        NULL_SERIAL_TYPE => 0,
-        // Value is an 8-bit twos-complement integer
-        EIGHT_BIT_SERIAL_TYPE => 1,
+        // Value is a null, or one of the constants 0 and 1
+        NULL_SERIAL_TYPE | ZERO_SERIAL_TYPE | ONE_SERIAL_TYPE => Ok(0),
+        // Value is a big-endian twos-complement integer
+        EIGHT_BIT_SERIAL_TYPE => Ok(1),
+        SIXTEEN_BIT_SERIAL_TYPE => Ok(2),
+        TWENTY_FOUR_BIT_SERIAL_TYPE => Ok(3),
+        THIRTY_TWO_BIT_SERIAL_TYPE => Ok(4),
+        FORTY_EIGHT_BIT_SERIAL_TYPE => Ok(6),
+        SIXTY_FOUR_BIT_SERIAL_TYPE => Ok(8),
+        // Value is a big-endian IEEE 754-2008 64-bit floating point number
+        FLOAT_SERIAL_TYPE => Ok(8),
+        // Value is a blob
+        serial_type_value if is_blob_serial_type(serial_type_value) => {
+            Ok(blob_serial_type_size(serial_type_value))
+        }
         // Value is a string
         serial_type_value if is_string_serial_type(serial_type_value) => {
-            string_serial_type_size(serial_type_value)
+            Ok(string_serial_type_size(serial_type_value))
         }
-        _ => todo!(),
+        // 10 and 11 are reserved for internal use
+        reserved => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Reserved serial type {reserved} in record"),
+        )),
     }
 }
 #[derive(Clone, Debug)]
 pub enum RecordValue {
     Null,
-    TwosComplement8(u8),
+    TwosComplement8(i8),
+    TwosComplement16(i16),
+    TwosComplement24(i32),
+    TwosComplement32(i32),
+    TwosComplement48(i64),
+    TwosComplement64(i64),
+    Float64(f64),
+    Zero,
+    One,
+    Blob(Vec<u8>),
     EncodedString(Vec<u8>),
 }
-fn lift_twos_complement_8(value: RecordValue) -> io::Result<u8> {
+pub fn lift_integer(value: RecordValue) -> io::Result<i64> {
     match value {
-        RecordValue::TwosComplement8(value) => Ok(value),
+        RecordValue::TwosComplement8(value) => Ok(value.into()),
+        RecordValue::TwosComplement16(value) => Ok(value.into()),
+        RecordValue::TwosComplement24(value) | RecordValue::TwosComplement32(value) => {
+            Ok(value.into())
+        }
+        RecordValue::TwosComplement48(value) | RecordValue::TwosComplement64(value) => Ok(value),
+        RecordValue::Zero => Ok(0),
+        RecordValue::One => Ok(1),
         otherwise => Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("Received {otherwise:?} when expecting an encoded string"),
+            format!("Received {otherwise:?} when expecting an integer"),
         )),
     }
 }
@@ -85,23 +124,260 @@ pub fn lift_encoded_string(value: RecordValue) -> io::Result<Vec<u8>> {
         )),
     }
 }
+/// Orders two values the way SQLite sorts them with the BINARY collation:
+/// NULLs first, then numbers, then text, then blobs
+pub fn compare(lhs: &RecordValue, rhs: &RecordValue) -> Ordering {
+    fn class(value: &RecordValue) -> u8 {
+        match value {
+            RecordValue::Null => 0,
+            RecordValue::EncodedString(_) => 2,
+            RecordValue::Blob(_) => 3,
+            _ => 1,
+        }
+    }
+    match (lhs, rhs) {
+        (RecordValue::EncodedString(lhs), RecordValue::EncodedString(rhs))
+        | (RecordValue::Blob(lhs), RecordValue::Blob(rhs)) => lhs.cmp(rhs),
+        (RecordValue::Float64(lhs), RecordValue::Float64(rhs)) => lhs.total_cmp(rhs),
+        (RecordValue::Float64(lhs), rhs) if class(rhs) == 1 => {
+            lift_integer(rhs.clone()).map_or(Ordering::Equal, |rhs| lhs.total_cmp(&(rhs as f64)))
+        }
+        (lhs, RecordValue::Float64(rhs)) if class(lhs) == 1 => {
+            lift_integer(lhs.clone()).map_or(Ordering::Equal, |lhs| (lhs as f64).total_cmp(rhs))
+        }
+        (lhs, rhs) if class(lhs) == 1 && class(rhs) == 1 => {
+            match (lift_integer(lhs.clone()), lift_integer(rhs.clone())) {
+                (Ok(lhs), Ok(rhs)) => lhs.cmp(&rhs),
+                _ => Ordering::Equal,
+            }
+        }
+        (lhs, rhs) => class(lhs).cmp(&class(rhs)),
+    }
+}
+/// The encoding of every text value in a database, as given by its header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8 = 1,
+    Utf16le = 2,
+    Utf16be = 3,
+}
+impl TryFrom<u32> for TextEncoding {
+    type Error = io::Error;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(TextEncoding::Utf8),
+            2 => Ok(TextEncoding::Utf16le),
+            3 => Ok(TextEncoding::Utf16be),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown text encoding {other}"),
+            )),
+        }
+    }
+}
+impl TextEncoding {
+    /// Decodes text as stored in the database, replacing invalid sequences
+    pub fn decode(self, bytes: &[u8]) -> String {
+        let utf16 = |word: fn([u8; 2]) -> u16| {
+            let words: Vec<u16> = bytes
+                .chunks_exact(core::mem::size_of::<u16>())
+                .map(|pair| word([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&words)
+        };
+        match self {
+            TextEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            TextEncoding::Utf16le => utf16(u16::from_le_bytes),
+            TextEncoding::Utf16be => utf16(u16::from_be_bytes),
+        }
+    }
+    /// Encodes text to be stored in the database
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            TextEncoding::Utf16be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+        }
+    }
+}
+impl fmt::Display for RecordValue {
+    /// Formats the value the way the sqlite3 shell prints query results
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordValue::Null => Ok(()),
+            RecordValue::Float64(value) => f.write_str(&format_real(*value)),
+            RecordValue::Blob(bytes) | RecordValue::EncodedString(bytes) => {
+                f.write_str(&String::from_utf8_lossy(bytes))
+            }
+            integer => match lift_integer(integer.clone()) {
+                Ok(value) => f.write_fmt(format_args!("{value}")),
+                Err(_) => Err(fmt::Error),
+            },
+        }
+    }
+}
+/// Formats a real the way SQLite's `%!.15g` does: 15 significant digits,
+/// always with a decimal point, switching to an exponent for very large
+/// or very small magnitudes
+fn format_real(value: f64) -> String {
+    const SIGNIFICANT_DIGITS: i32 = 15;
+    fn with_point(digits: &str) -> String {
+        match digits.split_once('.') {
+            Some((whole, fraction)) => match fraction.trim_end_matches('0') {
+                "" => format!("{whole}.0"),
+                fraction => format!("{whole}.{fraction}"),
+            },
+            None => format!("{digits}.0"),
+        }
+    }
+    if value.is_nan() {
+        return "NaN".to_string();
+    }
+    if value.is_infinite() {
+        return if value.is_sign_positive() {
+            "Inf"
+        } else {
+            "-Inf"
+        }
+        .to_string();
+    }
+    if value == 0.0 {
+        return "0.0".to_string();
+    }
+    let scientific = format!("{:.*e}", SIGNIFICANT_DIGITS as usize - 1, value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or_default();
+    if !(-4..SIGNIFICANT_DIGITS).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{sign}{:02}", with_point(mantissa), exponent.abs())
+    } else {
+        let precision = (SIGNIFICANT_DIGITS - 1 - exponent).max(0) as usize;
+        with_point(&format!("{value:.precision$}"))
+    }
+}
+/// Sign-extends the big-endian twos-complement integer in `bytes` to 64 bits
+fn sign_extend(bytes: &[u8]) -> i64 {
+    let unsigned = bytes.iter().fold(0u64, |acc, elt| (acc << 8) | *elt as u64);
+    let shift = 64 - 8 * bytes.len() as u32;
+    ((unsigned << shift) as i64) >> shift
+}
 pub fn read_value<R: io::Read>(r: &mut R, serial_type: &Varint) -> io::Result<RecordValue> {
-    const NULL_SERIAL_TYPE: u64 = 0;
-    const EIGHT_BIT_SERIAL_TYPE: u64 = 1;
-    match varint::value_of(serial_type) {
-        NULL_SERIAL_TYPE => Ok(RecordValue::Null),
-        EIGHT_BIT_SERIAL_TYPE => io::read_one(r).map(RecordValue::TwosComplement8),
-        serial_type_value if is_string_serial_type(serial_type_value) => {
-            io::read_exact_vec(r, string_serial_type_size(serial_type_value))
-                .map(RecordValue::EncodedString)
+    let bytes = io::read_exact_vec(r, serial_type_size(serial_type)?)?;
+    let value = match varint::value_of(serial_type) {
+        NULL_SERIAL_TYPE => RecordValue::Null,
+        EIGHT_BIT_SERIAL_TYPE => RecordValue::TwosComplement8(sign_extend(&bytes) as i8),
+        SIXTEEN_BIT_SERIAL_TYPE => RecordValue::TwosComplement16(sign_extend(&bytes) as i16),
+        TWENTY_FOUR_BIT_SERIAL_TYPE => RecordValue::TwosComplement24(sign_extend(&bytes) as i32),
+        THIRTY_TWO_BIT_SERIAL_TYPE => RecordValue::TwosComplement32(sign_extend(&bytes) as i32),
+        FORTY_EIGHT_BIT_SERIAL_TYPE => RecordValue::TwosComplement48(sign_extend(&bytes)),
+        SIXTY_FOUR_BIT_SERIAL_TYPE => RecordValue::TwosComplement64(sign_extend(&bytes)),
+        FLOAT_SERIAL_TYPE => {
+            let mut buf = [0; core::mem::size_of::<f64>()];
+            buf.copy_from_slice(&bytes);
+            RecordValue::Float64(f64::from_be_bytes(buf))
         }
-        _ => todo!(),
+        ZERO_SERIAL_TYPE => RecordValue::Zero,
+        ONE_SERIAL_TYPE => RecordValue::One,
+        serial_type_value if is_blob_serial_type(serial_type_value) => RecordValue::Blob(bytes),
+        _ => RecordValue::EncodedString(bytes),
+    };
+    Ok(value)
+}
+/// The smallest serial type that holds `value`.
+///
+/// The constants 0 and 1 take no body bytes, which needs file format 4.
+pub fn serial_type_of(value: &RecordValue) -> u64 {
+    match value {
+        RecordValue::Null => NULL_SERIAL_TYPE,
+        RecordValue::Float64(_) => FLOAT_SERIAL_TYPE,
+        RecordValue::Blob(bytes) => 12 + 2 * bytes.len() as u64,
+        RecordValue::EncodedString(bytes) => 13 + 2 * bytes.len() as u64,
+        integer => match lift_integer(integer.clone()).unwrap_or_default() {
+            0 => ZERO_SERIAL_TYPE,
+            1 => ONE_SERIAL_TYPE,
+            value if i8::try_from(value).is_ok() => EIGHT_BIT_SERIAL_TYPE,
+            value if i16::try_from(value).is_ok() => SIXTEEN_BIT_SERIAL_TYPE,
+            value if (-0x80_0000..0x80_0000).contains(&value) => TWENTY_FOUR_BIT_SERIAL_TYPE,
+            value if i32::try_from(value).is_ok() => THIRTY_TWO_BIT_SERIAL_TYPE,
+            value if (-0x8000_0000_0000..0x8000_0000_0000).contains(&value) => {
+                FORTY_EIGHT_BIT_SERIAL_TYPE
+            }
+            _ => SIXTY_FOUR_BIT_SERIAL_TYPE,
+        },
     }
 }
+/// Appends the body bytes of `value`, stored with the given serial type
+fn write_value(buf: &mut Vec<u8>, value: &RecordValue, serial_type: u64) -> io::Result<()> {
+    match value {
+        RecordValue::Null => {}
+        RecordValue::Float64(value) => buf.extend(value.to_be_bytes()),
+        RecordValue::Blob(bytes) => buf.extend(bytes),
+        RecordValue::EncodedString(bytes) => buf.extend(bytes),
+        integer => {
+            let value = lift_integer(integer.clone())?;
+            let size = serial_type_size(&Varint::from(serial_type))?;
+            buf.extend(&value.to_be_bytes()[core::mem::size_of::<i64>() - size..]);
+        }
+    }
+    Ok(())
+}
+/// Serializes values into a record: a header of the header size and
+/// the serial type of each value, all varints, followed by the body.
+///
+/// Text is held as UTF-8 and is re-encoded in the database text encoding.
+pub fn write_record(values: &[RecordValue], encoding: TextEncoding) -> io::Result<Vec<u8>> {
+    let values: Vec<RecordValue> = values
+        .iter()
+        .map(|value| match value {
+            RecordValue::EncodedString(text) if encoding != TextEncoding::Utf8 => {
+                RecordValue::EncodedString(encoding.encode(&String::from_utf8_lossy(text)))
+            }
+            value => value.clone(),
+        })
+        .collect();
+    let serial_types: Vec<u64> = values.iter().map(serial_type_of).collect();
+    let mut serial_type_bytes: Vec<u8> = serial_types
+        .iter()
+        .flat_map(|serial_type| varint::encode(*serial_type))
+        .collect();
+    // The header size counts its own varint, which may grow a byte as it does
+    let mut header_size = serial_type_bytes.len() as u64 + 1;
+    while varint::size_of(&varint::encode(header_size)) as u64 + serial_type_bytes.len() as u64
+        > header_size
+    {
+        header_size += 1;
+    }
+    let mut record: Vec<u8> = varint::encode(header_size).into_iter().collect();
+    record.append(&mut serial_type_bytes);
+    for (value, serial_type) in values.iter().zip(serial_types) {
+        write_value(&mut record, value, serial_type)?;
+    }
+    Ok(record)
+}
 #[derive(Debug)]
 pub struct RawColumn {
     pub cells: Vec<RecordValue>,
 }
+impl RawColumn {
+    /// Re-encodes the text values of the column as UTF-8,
+    /// which is how text is handled once read from the database
+    pub fn decode_text(self, encoding: TextEncoding) -> RawColumn {
+        if encoding == TextEncoding::Utf8 {
+            return self;
+        }
+        let cells = self
+            .cells
+            .into_iter()
+            .map(|cell| match cell {
+                RecordValue::EncodedString(bytes) => {
+                    RecordValue::EncodedString(encoding.decode(&bytes).into_bytes())
+                }
+                otherwise => otherwise,
+            })
+            .collect();
+        RawColumn { cells }
+    }
+}
 pub fn read_raw_column<'s, R: io::Read>(
     r: &mut R,
     serial_types: impl IntoIterator<Item = &'s Varint>,
@@ -128,26 +404,6 @@ impl FromRawColumn for RawColumn {
     }
 }
 #[derive(Debug)]
-pub struct Record<C> {
-    pub header: RecordHeader,
-    pub columns: Vec<C>,
-}
-pub fn read<R: io::Read, C: FromRawColumn>(r: &mut R) -> io::Result<Record<C>> {
-    let RawRecord { header, data } = read_raw(r)?;
-    let mut src = data.as_slice();
-    let columns = core::iter::from_fn(|| {
-        let column = read_raw_column(&mut src, header.serial_types.iter());
-        if column.cells.is_empty() {
-            eprintln!("Column cells empty");
-            None
-        } else {
-            FromRawColumn::from_raw_column(column).ok()
-        }
-    })
-    .collect();
-    Ok(Record { header, columns })
-}
-#[derive(Debug)]
 pub struct RecordBytes<'a> {
     pub header: RecordHeader,
     pub bytes: &'a [u8],
@@ -165,6 +421,7 @@ impl RecordBytes<'_> {
 }
 #[derive(Debug)]
 pub struct SerializedRecord {
+    #[allow(dead_code)]
     pub header: RecordHeader,
     pub column: RawColumn,
 }
@@ -179,3 +436,106 @@ impl SerializedRecord {
 //     let column = record::read_raw_column(&mut bytes, serial_types);
 //     record::SerializedRecord { header, column }
 // }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_text_in_each_encoding() {
+        let text = "crème brûlée";
+        let le: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let be: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(TextEncoding::Utf8.decode(text.as_bytes()), text);
+        assert_eq!(TextEncoding::Utf16le.decode(&le), text);
+        assert_eq!(TextEncoding::Utf16be.decode(&be), text);
+    }
+    #[test]
+    fn compares_in_sqlite_sort_order() {
+        let ascending = [
+            RecordValue::Null,
+            RecordValue::TwosComplement8(-3),
+            RecordValue::Float64(-2.5),
+            RecordValue::Zero,
+            RecordValue::Float64(0.5),
+            RecordValue::TwosComplement16(300),
+            RecordValue::EncodedString(b"B".to_vec()),
+            RecordValue::EncodedString(b"a".to_vec()),
+            RecordValue::Blob(vec![0]),
+        ];
+        for pair in ascending.windows(2) {
+            assert_eq!(compare(&pair[0], &pair[1]), Ordering::Less, "{pair:?}");
+            assert_eq!(compare(&pair[1], &pair[0]), Ordering::Greater, "{pair:?}");
+        }
+        assert_eq!(
+            compare(&RecordValue::One, &RecordValue::Float64(1.0)),
+            Ordering::Equal
+        );
+    }
+    #[test]
+    fn picks_the_smallest_serial_type() {
+        let serial_types: Vec<u64> = [
+            0,
+            1,
+            -1,
+            127,
+            128,
+            -32768,
+            0x7F_FFFF,
+            -0x8000_0000,
+            0x7FFF_FFFF_FFFF,
+            i64::MIN,
+        ]
+        .into_iter()
+        .map(|value| serial_type_of(&RecordValue::TwosComplement64(value)))
+        .collect();
+        assert_eq!(serial_types, [8, 9, 1, 1, 2, 2, 3, 4, 5, 6]);
+        let values = [
+            RecordValue::Null,
+            RecordValue::TwosComplement64(-300),
+            RecordValue::Float64(2.5),
+            RecordValue::EncodedString("crème".into()),
+            RecordValue::Blob(vec![0, 1]),
+        ];
+        let record = write_record(&values, TextEncoding::Utf16le).unwrap();
+        // Five characters of UTF-16 take ten bytes
+        assert_eq!(record[..6], [6, 0, 2, 7, 13 + 2 * 10, 12 + 2 * 2]);
+        let decoded = SerializedRecord::from_bytes(RecordBytes::from_bytes(&record).unwrap())
+            .column
+            .decode_text(TextEncoding::Utf16le);
+        let shown: Vec<String> = decoded.cells.iter().map(ToString::to_string).collect();
+        assert_eq!(shown, ["", "-300", "2.5", "crème", "\0\u{1}"]);
+    }
+    #[test]
+    fn records_of_sample_database_round_trip() {
+        use crate::database::{self, btree, page, pager};
+
+        let bytes = std::fs::read("sample.db").unwrap();
+        let header = database::read_header(&mut bytes.as_slice()).unwrap();
+        let pager = pager::Pager::new(io::Cursor::new(bytes), pager::layout_of(&header), 16);
+        let mut database = database::load(pager).unwrap();
+        let rootpages: Vec<u32> = core::iter::once(1)
+            .chain(
+                database
+                    .schema_cells
+                    .iter()
+                    .map(|schema| schema.column.rootpage),
+            )
+            .filter(|rootpage| *rootpage != 0)
+            .collect();
+        let mut records = 0;
+        for rootpage in rootpages {
+            for cell in page::read_btree(&mut database.pager, rootpage).unwrap() {
+                let cell = cell.unwrap();
+                let payload = btree::get_cell_content(&cell).unwrap();
+                let SerializedRecord { column, .. } =
+                    SerializedRecord::from_bytes(RecordBytes::from_bytes(payload).unwrap());
+                assert_eq!(
+                    write_record(&column.cells, TextEncoding::Utf8).unwrap(),
+                    payload
+                );
+                records += 1;
+            }
+        }
+        assert!(records > 0);
+    }
+}
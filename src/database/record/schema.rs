@@ -1,14 +1,82 @@
-use crate::database::record::{self, FromRawColumn, RawColumn, RecordHeader};
+use crate::database::record::{self, FromRawColumn, RawColumn, RecordHeader, RecordValue};
 use crate::io;
 use crate::sql;
 
+/// A schema object, with its SQL definition parsed
+#[derive(Debug)]
+pub enum SchemaObject {
+    Table(sql::SqlCreateTable),
+    /// Indexes SQLite creates for UNIQUE and PRIMARY KEY constraints have no SQL
+    #[allow(dead_code)]
+    Index(Option<sql::SqlCreateIndex>),
+    #[allow(dead_code)]
+    View(sql::SqlCreateView),
+    #[allow(dead_code)]
+    Trigger(sql::SqlCreateTrigger),
+    /// A definition whose SQL could not be parsed, with the reason why
+    Unreadable(String),
+}
+fn invalid_sql(r#type: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "expected create-{} sql for schema column",
+            String::from_utf8_lossy(r#type)
+        ),
+    )
+}
+fn read_object(r#type: &[u8], sql: Option<&[u8]>) -> io::Result<SchemaObject> {
+    let parsed = || {
+        sql.ok_or(invalid_sql(r#type))
+            .and_then(|sql| sql::parse(sql.iter().copied()))
+    };
+    match r#type {
+        b"table" => parsed()
+            .map(sql::lift_create_table)?
+            .map(SchemaObject::Table),
+        b"index" => match sql {
+            Some(_) => parsed()
+                .map(sql::lift_create_index)?
+                .map(|index| SchemaObject::Index(Some(index))),
+            None => Some(SchemaObject::Index(None)),
+        },
+        b"view" => parsed().map(sql::lift_create_view)?.map(SchemaObject::View),
+        b"trigger" => parsed()
+            .map(sql::lift_create_trigger)?
+            .map(SchemaObject::Trigger),
+        otherwise => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unknown schema object type {}",
+                    String::from_utf8_lossy(otherwise)
+                ),
+            ))
+        }
+    }
+    .ok_or(invalid_sql(r#type))
+}
 #[derive(Debug)]
 pub struct SchemaColumn {
     pub r#type: Vec<u8>,
     pub name: Vec<u8>,
+    /// The table an index or trigger belongs to, or the name of a table or view
     pub table_name: Vec<u8>,
-    pub rootpage: u8,
-    pub sql: sql::SqlCreateTable,
+    /// The root page of a table or index, zero for views and triggers
+    pub rootpage: u32,
+    /// The SQL text of the definition, which auto-indexes lack
+    #[allow(dead_code)]
+    pub sql: Option<Vec<u8>>,
+    pub object: SchemaObject,
+}
+impl SchemaColumn {
+    /// The definition of the object, if it is a table
+    pub fn table(&self) -> Option<&sql::SqlCreateTable> {
+        match &self.object {
+            SchemaObject::Table(table) => Some(table),
+            _ => None,
+        }
+    }
 }
 impl FromRawColumn for SchemaColumn {
     fn from_raw_column(column: RawColumn) -> io::Result<Self>
@@ -26,42 +94,45 @@ impl FromRawColumn for SchemaColumn {
         let r#type = next().and_then(record::lift_encoded_string)?;
         let name = next().and_then(record::lift_encoded_string)?;
         let table_name = next().and_then(record::lift_encoded_string)?;
-        let rootpage = next().and_then(record::lift_twos_complement_8)?;
-        let sql = next()
-            .and_then(record::lift_encoded_string)
-            .and_then(sql::parse)
-            .and_then(|elt| {
-                sql::lift_create_table(elt).ok_or(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "expected create-table sql for schema column",
-                ))
-            })?;
+        let rootpage = next().and_then(record::lift_integer).and_then(|rootpage| {
+            u32::try_from(rootpage).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })?;
+        let sql = match next()? {
+            RecordValue::Null => None,
+            sql => record::lift_encoded_string(sql).map(Some)?,
+        };
+        let object = read_object(&r#type, sql.as_deref())
+            .unwrap_or_else(|e| SchemaObject::Unreadable(e.to_string()));
         Ok(SchemaColumn {
             r#type,
             name,
             table_name,
             rootpage,
             sql,
+            object,
         })
     }
 }
 #[derive(Debug)]
 pub struct SchemaRecord {
+    #[allow(dead_code)]
     pub header: RecordHeader,
     pub column: SchemaColumn,
 }
+#[allow(dead_code)]
 pub fn pretty_print_schema_column(
     SchemaColumn {
         r#type,
         name,
         table_name,
         rootpage,
-        sql,
+        object,
+        ..
     }: &SchemaColumn,
 ) {
     eprintln!("TYPE={}", String::from_utf8_lossy(r#type));
     eprintln!("NAME={}", String::from_utf8_lossy(name));
     eprintln!("TABLE_NAME={}", String::from_utf8_lossy(table_name));
     eprintln!("ROOTPAGE={}", rootpage);
-    eprintln!("SQL={:?}", sql);
+    eprintln!("OBJECT={:?}", object);
 }
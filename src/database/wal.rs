@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use crate::io;
+
+const WAL_HEADER_SIZE: usize = 32;
+const WAL_FRAME_HEADER_SIZE: usize = 24;
+/// The WAL magic number, whose least significant bit
+/// gives the byte order of the checksums
+const WAL_MAGIC: u32 = 0x377f0682;
+const WAL_FORMAT_VERSION: u32 = 3007000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WalHeader {
+    /// Either 0x377f0682 for little-endian checksums, or 0x377f0683 for big-endian ones
+    pub magic: u32,
+    /// File format version, currently 3007000
+    pub format_version: u32,
+    pub page_size: u32,
+    #[allow(dead_code)]
+    pub checkpoint_sequence: u32,
+    /// Random values copied into every frame, changing with each checkpoint
+    pub salts: [u32; 2],
+    /// Checksum of the first 24 bytes of the header
+    pub checksum: [u32; 2],
+}
+#[derive(Clone, Copy, Debug)]
+pub struct WalFrameHeader {
+    pub page_number: u32,
+    /// The size of the database in pages after a commit frame, zero for other frames
+    pub database_size: u32,
+    pub salts: [u32; 2],
+    /// Cumulative checksum over the WAL up to and including this frame
+    pub checksum: [u32; 2],
+}
+fn be_u32(bytes: &[u8], word_idx: usize) -> u32 {
+    let offset = word_idx * core::mem::size_of::<u32>();
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+fn parse_wal_header(buf: &[u8; WAL_HEADER_SIZE]) -> WalHeader {
+    WalHeader {
+        magic: be_u32(buf, 0),
+        format_version: be_u32(buf, 1),
+        page_size: be_u32(buf, 2),
+        checkpoint_sequence: be_u32(buf, 3),
+        salts: [be_u32(buf, 4), be_u32(buf, 5)],
+        checksum: [be_u32(buf, 6), be_u32(buf, 7)],
+    }
+}
+fn parse_frame_header(buf: &[u8]) -> WalFrameHeader {
+    WalFrameHeader {
+        page_number: be_u32(buf, 0),
+        database_size: be_u32(buf, 1),
+        salts: [be_u32(buf, 2), be_u32(buf, 3)],
+        checksum: [be_u32(buf, 4), be_u32(buf, 5)],
+    }
+}
+/// Continues the WAL checksum `[s0, s1]` over `bytes`,
+/// whose length must be a multiple of 8.
+fn checksum(big_endian: bool, [mut s0, mut s1]: [u32; 2], bytes: &[u8]) -> [u32; 2] {
+    for pair in bytes.chunks_exact(2 * core::mem::size_of::<u32>()) {
+        let word = |offset: usize| {
+            let word = [
+                pair[offset],
+                pair[offset + 1],
+                pair[offset + 2],
+                pair[offset + 3],
+            ];
+            if big_endian {
+                u32::from_be_bytes(word)
+            } else {
+                u32::from_le_bytes(word)
+            }
+        };
+        s0 = s0.wrapping_add(word(0)).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(4)).wrapping_add(s0);
+    }
+    [s0, s1]
+}
+/// The committed contents of a write-ahead log
+#[derive(Debug)]
+pub struct Wal<R> {
+    file: R,
+    page_size: usize,
+    /// The offset of the latest committed frame of each page
+    frames: HashMap<u32, u64>,
+}
+/// Reads the frames of a log, up to the last commit frame whose salts and
+/// checksums are valid. Frames after it belong to a transaction that was
+/// never committed, or to a log from before the last checkpoint, and are ignored.
+fn read_frames<R: io::Read>(r: &mut R, page_size: usize) -> io::Result<HashMap<u32, u64>> {
+    let mut committed = HashMap::new();
+    let header_bytes: [u8; WAL_HEADER_SIZE] = match io::read_exact_array(r) {
+        Ok(header_bytes) => header_bytes,
+        // An empty log holds no frames
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(committed),
+        Err(e) => return Err(e),
+    };
+    let header = parse_wal_header(&header_bytes);
+    eprintln!("WAL HEADER={header:X?}");
+    let big_endian = header.magic & 1 == 1;
+    let mut running = checksum(big_endian, [0, 0], &header_bytes[..24]);
+    if header.magic & !1 != WAL_MAGIC
+        || header.format_version != WAL_FORMAT_VERSION
+        || header.page_size as usize != page_size
+        || running != header.checksum
+    {
+        eprintln!("IGNORING WAL WITH INVALID HEADER");
+        return Ok(committed);
+    }
+    let mut pending = HashMap::new();
+    let mut frame = vec![0; WAL_FRAME_HEADER_SIZE + page_size];
+    let mut offset = WAL_HEADER_SIZE as u64;
+    while io::Read::read_exact(r, &mut frame).is_ok() {
+        let frame_header = parse_frame_header(&frame);
+        if frame_header.salts != header.salts {
+            break;
+        }
+        running = checksum(big_endian, running, &frame[..8]);
+        running = checksum(big_endian, running, &frame[WAL_FRAME_HEADER_SIZE..]);
+        if running != frame_header.checksum {
+            break;
+        }
+        pending.insert(frame_header.page_number, offset);
+        if frame_header.database_size != 0 {
+            committed.extend(pending.drain());
+        }
+        offset += frame.len() as u64;
+    }
+    eprintln!(
+        "WAL COMMITTED PAGES={};UNCOMMITTED PAGES={}",
+        committed.len(),
+        pending.len()
+    );
+    Ok(committed)
+}
+impl<R: io::Read + io::Seek> Wal<R> {
+    pub fn read(mut file: R, page_size: usize) -> io::Result<Wal<R>> {
+        let frames = read_frames(&mut file, page_size)?;
+        Ok(Wal {
+            file,
+            page_size,
+            frames,
+        })
+    }
+    /// Reads the latest committed version of a page, if the log holds one
+    pub fn page(&mut self, page_idx: u32) -> Option<io::Result<Vec<u8>>> {
+        let offset = *self.frames.get(&page_idx)?;
+        let data_offset = offset + WAL_FRAME_HEADER_SIZE as u64;
+        Some(
+            io::Seek::seek(&mut self.file, io::SeekFrom::Start(data_offset))
+                .and_then(|_| io::read_exact_vec(&mut self.file, self.page_size)),
+        )
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 512;
+    const SALTS: [u32; 2] = [0x1234, 0x5678];
+
+    fn header(running: &mut [u32; 2]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = [WAL_MAGIC, WAL_FORMAT_VERSION, PAGE_SIZE as u32, 0]
+            .into_iter()
+            .chain(SALTS)
+            .flat_map(u32::to_be_bytes)
+            .collect();
+        *running = checksum(false, [0, 0], &bytes);
+        bytes.extend(running.iter().flat_map(|elt| elt.to_be_bytes()));
+        bytes
+    }
+    fn frame(running: &mut [u32; 2], page_number: u32, database_size: u32, fill: u8) -> Vec<u8> {
+        let mut bytes: Vec<u8> = [page_number, database_size]
+            .into_iter()
+            .chain(SALTS)
+            .flat_map(u32::to_be_bytes)
+            .collect();
+        let page = [fill; PAGE_SIZE];
+        *running = checksum(false, *running, &bytes[..8]);
+        *running = checksum(false, *running, &page);
+        bytes.extend(running.iter().flat_map(|elt| elt.to_be_bytes()));
+        bytes.extend(page);
+        bytes
+    }
+    #[test]
+    fn reads_latest_committed_frames() {
+        let mut running = [0, 0];
+        let mut log = header(&mut running);
+        log.extend(frame(&mut running, 2, 0, 0xA));
+        log.extend(frame(&mut running, 2, 0, 0xB));
+        log.extend(frame(&mut running, 3, 3, 0xC));
+        // Never committed
+        log.extend(frame(&mut running, 3, 0, 0xD));
+        let mut wal = Wal::read(io::Cursor::new(log), PAGE_SIZE).unwrap();
+        assert!(wal.page(1).is_none());
+        assert!(wal
+            .page(2)
+            .is_some_and(|page| page.unwrap() == [0xB; PAGE_SIZE]));
+        assert!(wal
+            .page(3)
+            .is_some_and(|page| page.unwrap() == [0xC; PAGE_SIZE]));
+    }
+    #[test]
+    fn stops_at_invalid_checksum() {
+        let mut running = [0, 0];
+        let mut log = header(&mut running);
+        log.extend(frame(&mut running, 2, 2, 0xA));
+        let corrupt_at = log.len() + WAL_FRAME_HEADER_SIZE;
+        log.extend(frame(&mut running, 2, 2, 0xB));
+        log[corrupt_at] ^= 0xFF;
+        let mut wal = Wal::read(io::Cursor::new(log), PAGE_SIZE).unwrap();
+        assert!(wal
+            .page(2)
+            .is_some_and(|page| page.unwrap() == [0xA; PAGE_SIZE]));
+    }
+}
@@ -0,0 +1,1354 @@
+use crate::database::btree::{self, BTreeCell, BTreePage, BTreePageType, PageMut};
+use crate::database::page::{self, header_offset, MAX_DEPTH};
+use crate::database::pager::{self, Pager};
+use crate::database::record::{self, RecordValue, SchemaRecord, TextEncoding};
+use crate::database::{Database, DatabaseHeader, SchemaObject};
+use crate::io;
+use crate::sql::{self, SqlCreateTable, SqlDelete, SqlInsert, SqlUpdate};
+use crate::varint;
+
+/// The pages from the root of a table b-tree down to the leaf where some rowid belongs
+#[derive(Debug)]
+pub struct TablePath {
+    /// Each interior page passed through, with the index of the child followed,
+    /// which is the cell count for the right-most child
+    pub ancestors: Vec<(u32, usize)>,
+    pub leaf: u32,
+}
+fn rowid_of(cell: &BTreeCell) -> Option<i64> {
+    match cell {
+        BTreeCell::LeafTable(btree::BTreeLeafTableCell { rowid, .. })
+        | BTreeCell::InteriorTable(btree::BTreeInteriorTableCell { rowid, .. }) => {
+            Some(varint::signed_value_of(rowid))
+        }
+        BTreeCell::LeafIndex(_) | BTreeCell::InteriorIndex(_) => None,
+    }
+}
+/// Descends the table b-tree rooted at `rootpage` to the leaf where `rowid` belongs
+pub fn find_leaf<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    rootpage: u32,
+    rowid: i64,
+) -> io::Result<TablePath> {
+    let mut ancestors = vec![];
+    let mut page_idx = rootpage;
+    loop {
+        let page = page::read_page_at(pager, page_idx)?;
+        let Some(right_most_pointer) = btree::right_most_pointer(&page) else {
+            return Ok(TablePath {
+                ancestors,
+                leaf: page_idx,
+            });
+        };
+        if ancestors.len() == MAX_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("table b-tree at root {rootpage} is deeper than {MAX_DEPTH} pages"),
+            ));
+        }
+        // Every rowid in the left child of a cell is at most the rowid of the cell
+        let child_idx = page
+            .content
+            .iter()
+            .position(|cell| rowid_of(cell).is_some_and(|key| rowid <= key))
+            .unwrap_or(page.content.len());
+        ancestors.push((page_idx, child_idx));
+        page_idx = match page.content.get(child_idx) {
+            Some(BTreeCell::InteriorTable(cell)) => cell.page_number,
+            _ => right_most_pointer,
+        };
+    }
+}
+/// Finds `rowid` among the cells of a leaf, or else where a cell with it would go
+fn search_leaf(page: &BTreePage, rowid: i64) -> Result<usize, usize> {
+    page.content
+        .binary_search_by_key(&rowid, |cell| rowid_of(cell).unwrap_or(i64::MIN))
+}
+/// The largest rowid in the table b-tree rooted at `rootpage`, None when it is empty
+pub fn max_rowid<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    rootpage: u32,
+) -> io::Result<Option<i64>> {
+    let TablePath { leaf, .. } = find_leaf(pager, rootpage, i64::MAX)?;
+    let page = page::read_page_at(pager, leaf)?;
+    Ok(page.content.last().and_then(rowid_of))
+}
+fn get_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        page[offset],
+        page[offset + 1],
+        page[offset + 2],
+        page[offset + 3],
+    ])
+}
+fn put_u32(page: &mut [u8], offset: usize, value: u32) {
+    page[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+/// The offsets of the fields of a freelist trunk page
+const NEXT_TRUNK_FIELD: usize = 0;
+const LEAF_COUNT_FIELD: usize = 4;
+const LEAVES_FIELD: usize = 8;
+/// The offset of the page number of the next overflow page, in an overflow page
+const NEXT_OVERFLOW_FIELD: usize = 0;
+/// Takes a zeroed page off the freelist, or else appends one to the database
+fn allocate_page<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+) -> io::Result<u32> {
+    let trunk_idx = header.freelist_page_idx;
+    let page_idx = if trunk_idx == 0 {
+        header.in_header_database_size += 1;
+        if header.in_header_database_size == pager::pending_byte_page(pager.layout()) {
+            header.in_header_database_size += 1;
+        }
+        header.in_header_database_size
+    } else {
+        let mut trunk = pager.page(trunk_idx)?.to_vec();
+        let leaf_count = get_u32(&trunk, LEAF_COUNT_FIELD) as usize;
+        if LEAVES_FIELD + 4 * leaf_count > pager.layout().usable_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("freelist trunk page {trunk_idx} claims {leaf_count} leaves"),
+            ));
+        }
+        header.freelist_page_count = header.freelist_page_count.saturating_sub(1);
+        match leaf_count.checked_sub(1) {
+            // The last leaf is taken, so that no other leaf moves
+            Some(leaf_idx) => {
+                let leaf = get_u32(&trunk, LEAVES_FIELD + 4 * leaf_idx);
+                put_u32(&mut trunk, LEAF_COUNT_FIELD, leaf_idx as u32);
+                pager.write_page(trunk_idx, trunk)?;
+                leaf
+            }
+            // A trunk without leaves is taken itself
+            None => {
+                header.freelist_page_idx = get_u32(&trunk, NEXT_TRUNK_FIELD);
+                trunk_idx
+            }
+        }
+    };
+    pager.write_page(page_idx, vec![0; pager.layout().page_size])?;
+    Ok(page_idx)
+}
+/// Puts a page no longer in use on the freelist, as a leaf of
+/// the first trunk while it has room, or else as the new first trunk
+fn free_page<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    page_idx: u32,
+) -> io::Result<()> {
+    let layout = pager.layout();
+    // SQLite leaves the last few slots of a trunk empty, as older versions misread full trunks
+    let max_leaves = layout.usable_size / 4 - 8;
+    let trunk_idx = header.freelist_page_idx;
+    header.freelist_page_count += 1;
+    if trunk_idx != 0 {
+        let mut trunk = pager.page(trunk_idx)?.to_vec();
+        let leaf_count = get_u32(&trunk, LEAF_COUNT_FIELD) as usize;
+        if leaf_count < max_leaves {
+            put_u32(&mut trunk, LEAVES_FIELD + 4 * leaf_count, page_idx);
+            put_u32(&mut trunk, LEAF_COUNT_FIELD, leaf_count as u32 + 1);
+            return pager.write_page(trunk_idx, trunk);
+        }
+    }
+    let mut trunk = vec![0; layout.page_size];
+    put_u32(&mut trunk, NEXT_TRUNK_FIELD, trunk_idx);
+    header.freelist_page_idx = page_idx;
+    pager.write_page(page_idx, trunk)
+}
+/// Writes the part of a payload that does not fit on its b-tree page onto
+/// a chain of new overflow pages, giving the first of them
+fn write_overflow<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    overflow: &[u8],
+) -> io::Result<u32> {
+    let layout = pager.layout();
+    let chunk_size = layout.usable_size - core::mem::size_of::<u32>();
+    let page_idxs = overflow
+        .chunks(chunk_size)
+        .map(|_| allocate_page(pager, header))
+        .collect::<io::Result<Vec<_>>>()?;
+    for (chunk_idx, chunk) in overflow.chunks(chunk_size).enumerate() {
+        let next = page_idxs.get(chunk_idx + 1).copied().unwrap_or(0);
+        let mut page = vec![0; layout.page_size];
+        page[..4].copy_from_slice(&next.to_be_bytes());
+        page[4..4 + chunk.len()].copy_from_slice(chunk);
+        pager.write_page(page_idxs[chunk_idx], page)?;
+    }
+    Ok(page_idxs[0])
+}
+/// Frees the overflow pages a table leaf cell spills onto
+fn free_overflow<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    cell: &BTreeCell,
+) -> io::Result<()> {
+    let BTreeCell::LeafTable(btree::BTreeLeafTableCell {
+        total_payload_bytes,
+        initial_payload,
+        first_overflow_page_number: Some(first_overflow_page_number),
+        ..
+    }) = cell
+    else {
+        return Ok(());
+    };
+    let chunk_size = pager.layout().usable_size - core::mem::size_of::<u32>();
+    let mut remaining =
+        (varint::value_of(total_payload_bytes) as usize).saturating_sub(initial_payload.len());
+    let mut page_idx = *first_overflow_page_number;
+    while remaining > 0 {
+        if page_idx == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("overflow chain ended with {remaining} payload bytes left"),
+            ));
+        }
+        let next = get_u32(&pager.page(page_idx)?, NEXT_OVERFLOW_FIELD);
+        free_page(pager, header, page_idx)?;
+        remaining = remaining.saturating_sub(chunk_size);
+        page_idx = next;
+    }
+    Ok(())
+}
+/// What to do when a row with the same rowid is already in the table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    Abort,
+    Replace,
+}
+/// Inserts a row into the table b-tree rooted at `rootpage`, keeping its leaf in rowid order
+pub fn insert_row<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    rootpage: u32,
+    rowid: i64,
+    payload: &[u8],
+    conflict: Conflict,
+) -> io::Result<()> {
+    let usable_size = pager.layout().usable_size;
+    let TablePath { ancestors, leaf } = find_leaf(pager, rootpage, rowid)?;
+    let leaf_page = page::read_page_at(pager, leaf)?;
+    let position = search_leaf(&leaf_page, rowid);
+    match (position, conflict) {
+        (Ok(_), Conflict::Abort) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("rowid {rowid} is already in the table at root {rootpage}"),
+            ))
+        }
+        (Ok(cell_idx), Conflict::Replace) => {
+            free_overflow(pager, header, &leaf_page.content[cell_idx])?;
+        }
+        (Err(_), _) => {}
+    }
+    let local = btree::local_table_payload_size(payload.len(), usable_size);
+    let first_overflow_page_number = if local < payload.len() {
+        Some(write_overflow(pager, header, &payload[local..])?)
+    } else {
+        None
+    };
+    let cell = btree::leaf_table_cell(rowid, payload, first_overflow_page_number, usable_size);
+    let mut page = pager.page(leaf)?.to_vec();
+    let mut page_mut = PageMut {
+        page: &mut page,
+        header_offset: header_offset(leaf),
+        usable_size,
+    };
+    let mut cells = page_mut.cells()?;
+    match position {
+        Ok(cell_idx) => {
+            if page_mut.replace_cell(cell_idx, &cell)? {
+                return pager.write_page(leaf, page);
+            }
+            page_mut.remove_cell(cell_idx)?;
+            cells[cell_idx] = cell.clone();
+            if page_mut.insert_cell(cell_idx, &cell)? {
+                return pager.write_page(leaf, page);
+            }
+        }
+        Err(cell_idx) => {
+            if page_mut.insert_cell(cell_idx, &cell)? {
+                return pager.write_page(leaf, page);
+            }
+            cells.insert(cell_idx, cell);
+        }
+    }
+    let node = Node {
+        r#type: BTreePageType::LeafTable,
+        cells,
+        right_most_pointer: None,
+    };
+    write_node(pager, header, ancestors, leaf, node)
+}
+/// Deletes the row with `rowid` from the table b-tree rooted at `rootpage`
+/// along with its overflow pages, giving whether there was such a row
+pub fn delete_row<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    rootpage: u32,
+    rowid: i64,
+) -> io::Result<bool> {
+    let TablePath { ancestors, leaf } = find_leaf(pager, rootpage, rowid)?;
+    let leaf_page = page::read_page_at(pager, leaf)?;
+    let Ok(cell_idx) = search_leaf(&leaf_page, rowid) else {
+        return Ok(false);
+    };
+    free_overflow(pager, header, &leaf_page.content[cell_idx])?;
+    let mut page = pager.page(leaf)?.to_vec();
+    let mut page_mut = PageMut {
+        page: &mut page,
+        header_offset: header_offset(leaf),
+        usable_size: pager.layout().usable_size,
+    };
+    page_mut.remove_cell(cell_idx)?;
+    let is_empty = page_mut.cell_count() == 0;
+    pager.write_page(leaf, page)?;
+    if is_empty {
+        remove_empty_page(pager, header, ancestors, leaf)?;
+    }
+    Ok(true)
+}
+/// Frees a page left without cells and drops the pointer to it from its parent.
+///
+/// A parent left without cells of its own is replaced by its one remaining child,
+/// or if it is the root, takes over the content of that child when it fits.
+/// The root itself is never freed, becoming an empty leaf instead.
+fn remove_empty_page<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    mut ancestors: Vec<(u32, usize)>,
+    mut page_idx: u32,
+) -> io::Result<()> {
+    while let Some((parent_idx, child_idx)) = ancestors.pop() {
+        free_page(pager, header, page_idx)?;
+        let mut parent = read_node(pager, parent_idx)?;
+        if child_idx < parent.cells.len() {
+            parent.cells.remove(child_idx);
+        } else if let Some(last) = parent.cells.pop() {
+            // The child left of the last divider becomes the right-most child
+            parent.right_most_pointer = Some(btree::left_child_page_number(&last)?);
+        } else {
+            // The parent had no other child, so it is empty too
+            page_idx = parent_idx;
+            continue;
+        }
+        if !parent.cells.is_empty() {
+            return write_fitting_node(pager, parent_idx, &parent);
+        }
+        let only_child = parent.right_most_pointer.unwrap_or_default();
+        return match ancestors.last() {
+            Some(&(grandparent_idx, parent_pos)) => {
+                let mut grandparent = read_node(pager, grandparent_idx)?;
+                match grandparent.cells.get_mut(parent_pos) {
+                    Some(cell) => cell[..4].copy_from_slice(&only_child.to_be_bytes()),
+                    None => grandparent.right_most_pointer = Some(only_child),
+                }
+                write_fitting_node(pager, grandparent_idx, &grandparent)?;
+                free_page(pager, header, parent_idx)
+            }
+            None => {
+                let child = read_node(pager, only_child)?;
+                if try_write_node(pager, parent_idx, &child)? {
+                    free_page(pager, header, only_child)?;
+                } else {
+                    write_fitting_node(pager, parent_idx, &parent)?;
+                }
+                Ok(())
+            }
+        };
+    }
+    let root = Node {
+        r#type: BTreePageType::LeafTable,
+        cells: vec![],
+        right_most_pointer: None,
+    };
+    write_fitting_node(pager, page_idx, &root)
+}
+/// What a table b-tree page holds, which may be more than fits on it
+#[derive(Debug)]
+struct Node {
+    r#type: BTreePageType,
+    cells: Vec<Vec<u8>>,
+    right_most_pointer: Option<u32>,
+}
+fn read_node<R: io::Read + io::Seek>(pager: &mut Pager<R>, page_idx: u32) -> io::Result<Node> {
+    let mut page = pager.page(page_idx)?.to_vec();
+    let page_mut = PageMut {
+        page: &mut page,
+        header_offset: header_offset(page_idx),
+        usable_size: pager.layout().usable_size,
+    };
+    Ok(Node {
+        r#type: page_mut.r#type()?,
+        cells: page_mut.cells()?,
+        right_most_pointer: page_mut.right_most_pointer()?,
+    })
+}
+/// Writes `node` to its page, if it fits
+fn try_write_node<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    page_idx: u32,
+    node: &Node,
+) -> io::Result<bool> {
+    let mut page = pager.page(page_idx)?.to_vec();
+    let mut page_mut = PageMut {
+        page: &mut page,
+        header_offset: header_offset(page_idx),
+        usable_size: pager.layout().usable_size,
+    };
+    if !page_mut.rebuild(node.r#type, &node.cells, node.right_most_pointer)? {
+        return Ok(false);
+    }
+    pager.write_page(page_idx, page)?;
+    Ok(true)
+}
+/// Writes `node` to its page, which it is known to fit
+fn write_fitting_node<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    page_idx: u32,
+    node: &Node,
+) -> io::Result<()> {
+    match try_write_node(pager, page_idx, node)? {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("cells expected to fit do not fit on page {page_idx}"),
+        )),
+    }
+}
+/// Writes `node` to the page at the end of `ancestors`, balancing it with its
+/// siblings when it does not fit, and so on up the tree for their parent.
+///
+/// When the root does not fit, its content moves to a new child
+/// and the root becomes an interior page over it, so that the
+/// rootpage of the table never changes.
+fn write_node<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    mut ancestors: Vec<(u32, usize)>,
+    mut page_idx: u32,
+    mut node: Node,
+) -> io::Result<()> {
+    while !try_write_node(pager, page_idx, &node)? {
+        let Some((parent_idx, child_idx)) = ancestors.pop() else {
+            let child_page_idx = allocate_page(pager, header)?;
+            let root = Node {
+                r#type: BTreePageType::InteriorTable,
+                cells: vec![],
+                right_most_pointer: Some(child_page_idx),
+            };
+            write_fitting_node(pager, page_idx, &root)?;
+            ancestors.push((page_idx, 0));
+            page_idx = child_page_idx;
+            continue;
+        };
+        node = balance_siblings(pager, header, parent_idx, child_idx, page_idx, node)?;
+        page_idx = parent_idx;
+    }
+    Ok(())
+}
+/// Spreads the cells of the overfull child `child_idx` of `parent_idx`, and those of
+/// its siblings on either side, over as many pages as they need, giving what the
+/// parent holds once its cells point to the new pages.
+fn balance_siblings<R: io::Read + io::Write + io::Seek>(
+    pager: &mut Pager<R>,
+    header: &mut DatabaseHeader,
+    parent_idx: u32,
+    child_idx: usize,
+    overfull_idx: u32,
+    overfull: Node,
+) -> io::Result<Node> {
+    let parent = read_node(pager, parent_idx)?;
+    let child_count = parent.cells.len() + 1;
+    let siblings = child_idx.saturating_sub(1)..(child_idx + 2).min(child_count);
+    let child_page_number = |idx: usize| match parent.cells.get(idx) {
+        Some(cell) => btree::left_child_page_number(cell),
+        None => parent.right_most_pointer.ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("interior page {parent_idx} has no right-most pointer"),
+        )),
+    };
+    // The cells of the siblings in key order, with the dividers between interior
+    // siblings brought down from the parent as cells pointing to their left child
+    let mut old_pages = vec![];
+    let mut cells = vec![];
+    let mut right_most_pointer = None;
+    let r#type = overfull.r#type;
+    let mut overfull = Some(overfull);
+    for idx in siblings.clone() {
+        let page_idx = child_page_number(idx)?;
+        let node = match page_idx == overfull_idx {
+            true => overfull.take().ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("page {page_idx} is a child of page {parent_idx} twice"),
+            ))?,
+            false => read_node(pager, page_idx)?,
+        };
+        if node.r#type != r#type {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("children of page {parent_idx} are of different types"),
+            ));
+        }
+        old_pages.push(page_idx);
+        cells.extend(node.cells);
+        match (node.right_most_pointer, parent.cells.get(idx)) {
+            (Some(child), Some(divider)) if idx + 1 < siblings.end => {
+                let rowid = btree::table_cell_rowid(divider, BTreePageType::InteriorTable)?;
+                cells.push(btree::interior_table_cell(child, rowid));
+            }
+            (child, _) => right_most_pointer = child,
+        }
+    }
+    let capacity = btree::cell_capacity(r#type, 0, pager.layout().usable_size);
+    let pages = distribute(&cells, r#type, capacity)?;
+    let mut new_pages = vec![];
+    for page_idx in 0..pages.len() {
+        new_pages.push(match old_pages.get(page_idx) {
+            Some(page_idx) => *page_idx,
+            None => allocate_page(pager, header)?,
+        });
+    }
+    for page_idx in old_pages.iter().skip(pages.len()) {
+        free_page(pager, header, *page_idx)?;
+    }
+    let mut dividers = vec![];
+    for (page_idx, (range, next)) in new_pages.iter().copied().zip(&pages) {
+        let node = match r#type {
+            BTreePageType::InteriorTable => Node {
+                r#type,
+                cells: cells[range.clone()].to_vec(),
+                right_most_pointer: match next {
+                    Some(divider) => Some(btree::left_child_page_number(&cells[*divider])?),
+                    None => right_most_pointer,
+                },
+            },
+            _ => Node {
+                r#type,
+                cells: cells[range.clone()].to_vec(),
+                right_most_pointer: None,
+            },
+        };
+        write_fitting_node(pager, page_idx, &node)?;
+        if next.is_some() {
+            // Leaf siblings are divided by their largest rowid, interior ones
+            // by the rowid of the cell taken out from between them
+            let key_cell = match r#type {
+                BTreePageType::InteriorTable => &cells[range.end],
+                _ => &cells[range.end - 1],
+            };
+            let rowid = btree::table_cell_rowid(key_cell, r#type)?;
+            dividers.push(btree::interior_table_cell(page_idx, rowid));
+        }
+    }
+    let last_page = new_pages[new_pages.len() - 1];
+    let mut parent_cells = parent.cells[..siblings.start].to_vec();
+    parent_cells.extend(dividers);
+    let mut parent_right_most_pointer = parent.right_most_pointer;
+    match parent.cells.get(siblings.end - 1) {
+        // The last sibling keeps the divider above it, now pointing to the last new page
+        Some(divider) => {
+            let rowid = btree::table_cell_rowid(divider, BTreePageType::InteriorTable)?;
+            parent_cells.push(btree::interior_table_cell(last_page, rowid));
+            parent_cells.extend_from_slice(&parent.cells[siblings.end..]);
+        }
+        None => parent_right_most_pointer = Some(last_page),
+    }
+    Ok(Node {
+        r#type: BTreePageType::InteriorTable,
+        cells: parent_cells,
+        right_most_pointer: parent_right_most_pointer,
+    })
+}
+/// Splits `cells` into the fewest pages of `capacity` bytes that hold them, with
+/// about as many bytes on each, giving the range of cells on each page and, for
+/// interior pages, the index of the cell taken out to divide it from the next.
+fn distribute(
+    cells: &[Vec<u8>],
+    r#type: BTreePageType,
+    capacity: usize,
+) -> io::Result<Vec<(core::ops::Range<usize>, Option<usize>)>> {
+    let interior = r#type == BTreePageType::InteriorTable;
+    'pages: for page_count in 1..=cells.len() {
+        let mut pages = vec![];
+        let mut start = 0;
+        for page_idx in 0..page_count {
+            let is_last = page_idx + 1 == page_count;
+            let remaining = btree::size_of_cells(&cells[start..]);
+            let target = remaining / (page_count - page_idx);
+            let mut end = start;
+            let mut size = 0;
+            while end < cells.len() {
+                let cell_size = btree::size_of_cells(&cells[end..end + 1]);
+                if size + cell_size > capacity || (!is_last && end > start && size >= target) {
+                    break;
+                }
+                size += cell_size;
+                end += 1;
+            }
+            if end == start {
+                continue 'pages;
+            }
+            if is_last {
+                if end < cells.len() {
+                    continue 'pages;
+                }
+                pages.push((start..end, None));
+            } else if interior {
+                // The cell after the page is taken out to divide it from the next
+                if end + 1 >= cells.len() {
+                    continue 'pages;
+                }
+                pages.push((start..end, Some(end)));
+                start = end + 1;
+            } else {
+                if end == cells.len() {
+                    continue 'pages;
+                }
+                pages.push((start..end, Some(end)));
+                start = end;
+            }
+        }
+        return Ok(pages);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} cells do not fit on pages of {capacity} bytes",
+            cells.len()
+        ),
+    ))
+}
+/// A record of `table` holding the DEFAULT value of each column, or NULL
+fn default_record(table: &SqlCreateTable) -> Vec<RecordValue> {
+    table
+        .columns()
+        .iter()
+        .map(|name| {
+            table
+                .defaults
+                .get(*name)
+                .cloned()
+                .unwrap_or(RecordValue::Null)
+        })
+        .collect()
+}
+/// Takes the value of the INTEGER PRIMARY KEY out of a record, as the rowid is
+/// stored in the cell and leaves NULL in the record, giving None if it is NULL
+fn take_rowid(table: &SqlCreateTable, record: &mut [RecordValue]) -> io::Result<Option<i64>> {
+    let Some((term_idx, _)) = table
+        .rowid_alias()
+        .and_then(|alias| table.signature.get(alias))
+    else {
+        return Ok(None);
+    };
+    match core::mem::replace(&mut record[*term_idx], RecordValue::Null) {
+        RecordValue::Null => Ok(None),
+        value => record::lift_integer(value)
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datatype mismatch")),
+    }
+}
+/// Checks that no NOT NULL column of a record is NULL, the rowid alias aside
+fn check_not_null(table: &SqlCreateTable, record: &[RecordValue]) -> io::Result<()> {
+    for (name, value) in table.columns().iter().zip(record) {
+        let (_, definition) = &table.signature[*name];
+        let is_rowid = table.rowid_alias() == Some(*name);
+        if matches!(value, RecordValue::Null) && definition.contains("not null") && !is_rowid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("NOT NULL constraint failed: {}.{name}", table.name),
+            ));
+        }
+    }
+    Ok(())
+}
+/// Names the rowid of `table` in the error for a rowid already in use
+fn unique_constraint(table: &SqlCreateTable, e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::AlreadyExists => io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "UNIQUE constraint failed: {}.{}",
+                table.name,
+                table.rowid_alias().unwrap_or("rowid")
+            ),
+        ),
+        _ => e,
+    }
+}
+/// The record of a row to insert, and its rowid if the INTEGER PRIMARY KEY is given
+fn table_record(
+    table: &SqlCreateTable,
+    columns: &[String],
+    values: &[sql::Expr],
+) -> io::Result<(Option<i64>, Vec<RecordValue>)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let columns: Vec<&str> = match columns {
+        [] => table.columns(),
+        columns => columns.iter().map(String::as_str).collect(),
+    };
+    if columns.len() != values.len() {
+        return Err(invalid(format!(
+            "table {} has {} columns but {} values were supplied",
+            table.name,
+            columns.len(),
+            values.len()
+        )));
+    }
+    let mut record = default_record(table);
+    for (column, value) in columns.iter().zip(values) {
+        let (Some((term_idx, _)), Some(affinity)) =
+            (table.signature.get(*column), table.affinity(column))
+        else {
+            return Err(invalid(format!(
+                "table {} has no column named {column}",
+                table.name
+            )));
+        };
+        record[*term_idx] = sql::apply_affinity(value.evaluate(&sql::NoColumns)?, affinity);
+    }
+    let rowid = take_rowid(table, &mut record)?;
+    check_not_null(table, &record)?;
+    Ok((rowid, record))
+}
+/// The rowid and record of a row once the assignments of an UPDATE statement
+/// are made, each evaluated against the row as it was
+fn updated_record(
+    row: &sql::TableRow,
+    assignments: &[(String, sql::Expr)],
+) -> io::Result<(i64, Vec<RecordValue>)> {
+    let sql::TableRow {
+        table,
+        rowid,
+        cells,
+        ..
+    } = *row;
+    let rowid = rowid.ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "rows to update need a rowid",
+    ))?;
+    // Records written before columns were added lack the trailing ones
+    let mut record = default_record(table);
+    for (value, cell) in record.iter_mut().zip(cells) {
+        *value = cell.clone();
+    }
+    // The INTEGER PRIMARY KEY reads as the rowid, which the pseudo-columns set too
+    let alias_idx = table
+        .rowid_alias()
+        .and_then(|alias| table.signature.get(alias))
+        .map(|(term_idx, _)| *term_idx);
+    if let Some(alias_idx) = alias_idx {
+        record[alias_idx] = RecordValue::TwosComplement64(rowid);
+    }
+    let mut new_rowid = rowid;
+    for (column, value) in assignments {
+        let value = value.evaluate(row)?;
+        match (table.signature.get(column), table.affinity(column)) {
+            (Some((term_idx, _)), Some(affinity)) => {
+                record[*term_idx] = sql::apply_affinity(value, affinity);
+            }
+            _ if sql::ROWID_NAMES.contains(&column.as_str()) => {
+                let value = sql::apply_affinity(value, sql::Affinity::Integer);
+                let value = record::lift_integer(value).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "datatype mismatch")
+                })?;
+                match alias_idx {
+                    Some(alias_idx) => record[alias_idx] = RecordValue::TwosComplement64(value),
+                    None => new_rowid = value,
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no such column: {column}"),
+                ))
+            }
+        }
+    }
+    if alias_idx.is_some() {
+        // Unlike on insert, a NULL INTEGER PRIMARY KEY does not pick a new rowid
+        new_rowid = take_rowid(table, &mut record)?.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "datatype mismatch",
+        ))?;
+    }
+    check_not_null(table, &record)?;
+    Ok((new_rowid, record))
+}
+/// The name of the table whose largest rowid ever used is kept in `sqlite_sequence`
+const SEQUENCE_TABLE: &[u8] = b"sqlite_sequence";
+/// Looks up the row of `table_name` in `sqlite_sequence`, giving its rowid and sequence number
+fn read_sequence<R: io::Read + io::Seek>(
+    pager: &mut Pager<R>,
+    rootpage: u32,
+    table_name: &str,
+    encoding: TextEncoding,
+) -> io::Result<Option<(i64, i64)>> {
+    for cell in page::read_table(pager, rootpage, encoding)? {
+        let page::RecordCell { rowid, record } = cell?;
+        let mut cells = record.column.cells.into_iter();
+        let (Some(RecordValue::EncodedString(name)), Some(seq)) = (cells.next(), cells.next())
+        else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case(table_name.as_bytes()) {
+            return record::lift_integer(seq).map(|seq| Some((rowid, seq)));
+        }
+    }
+    Ok(None)
+}
+/// Finds the table named `name` among the schema, giving its definition, rootpage and name
+/// as the schema spells it, so long as it is a table the write path can change.
+///
+/// Indexes and triggers whose SQL cannot be read still name their table,
+/// so they rule out writes to it like any other.
+fn writable_table<'s>(
+    schema_cells: &'s [SchemaRecord],
+    name: &str,
+) -> io::Result<(&'s SqlCreateTable, u32, String)> {
+    let dependents = schema_cells.iter().any(|schema| {
+        matches!(schema.column.r#type.as_slice(), b"index" | b"trigger")
+            && schema
+                .column
+                .table_name
+                .eq_ignore_ascii_case(name.as_bytes())
+    });
+    let Some(schema) = schema_cells.iter().find(|schema| {
+        schema.column.name.eq_ignore_ascii_case(name.as_bytes())
+            && matches!(schema.column.r#type.as_slice(), b"table" | b"view")
+    }) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such table: {name}"),
+        ));
+    };
+    if let SchemaObject::Unreadable(e) = &schema.column.object {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot modify {name} as its definition cannot be read: {e}"),
+        ));
+    }
+    let (Some(table), rootpage) = (schema.column.table(), schema.column.rootpage) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot modify {name} because it is a view"),
+        ));
+    };
+    if table.without_rowid || dependents {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "writing to {name} is not yet supported, as it is a WITHOUT ROWID table or has indexes or triggers"
+            ),
+        ));
+    }
+    let table_name = String::from_utf8_lossy(&schema.column.name).into_owned();
+    Ok((table, rootpage, table_name))
+}
+/// Inserts every row of an INSERT statement, giving the number inserted.
+/// Nothing is committed, so that a failing row leaves the file as it was.
+pub fn insert<R: io::Read + io::Write + io::Seek>(
+    database: &mut Database<R>,
+    insert: &SqlInsert,
+) -> io::Result<usize> {
+    let Database {
+        header,
+        schema_cells,
+        pager,
+        encoding,
+    } = database;
+    let (table, rootpage, table_name) = writable_table(schema_cells, &insert.table)?;
+    let sequence = match table.autoincrement() {
+        true => {
+            let Some(sequence) = schema_cells
+                .iter()
+                .find(|schema| schema.column.name == SEQUENCE_TABLE)
+            else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "AUTOINCREMENT table without sqlite_sequence",
+                ));
+            };
+            let rootpage = sequence.column.rootpage;
+            Some((
+                rootpage,
+                read_sequence(pager, rootpage, &table_name, *encoding)?,
+            ))
+        }
+        false => None,
+    };
+    let seq = sequence.and_then(|(_, row)| row).map_or(0, |(_, seq)| seq);
+    let mut largest = max_rowid(pager, rootpage)?.unwrap_or(0).max(seq);
+    for values in &insert.rows {
+        let (rowid, values) = table_record(table, &insert.columns, values)?;
+        let rowid = match rowid {
+            Some(rowid) => rowid,
+            None => largest.checked_add(1).ok_or(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the largest rowid is in use, and picking a free one is not supported",
+            ))?,
+        };
+        let payload = record::write_record(&values, *encoding)?;
+        insert_row(pager, header, rootpage, rowid, &payload, Conflict::Abort)
+            .map_err(|e| unique_constraint(table, e))?;
+        largest = largest.max(rowid);
+    }
+    if let Some((sequence_rootpage, row)) = sequence {
+        if largest > seq || row.is_none() {
+            let seq_rowid = match row {
+                Some((seq_rowid, _)) => seq_rowid,
+                None => max_rowid(pager, sequence_rootpage)?.unwrap_or(0) + 1,
+            };
+            let values = [
+                RecordValue::EncodedString(table_name.into_bytes()),
+                RecordValue::TwosComplement64(largest),
+            ];
+            let payload = record::write_record(&values, *encoding)?;
+            insert_row(
+                pager,
+                header,
+                sequence_rootpage,
+                seq_rowid,
+                &payload,
+                Conflict::Replace,
+            )?;
+        }
+    }
+    Ok(insert.rows.len())
+}
+/// Deletes the rows of a table that a DELETE statement matches, giving the number deleted
+pub fn delete<R: io::Read + io::Write + io::Seek>(
+    database: &mut Database<R>,
+    delete: &SqlDelete,
+) -> io::Result<usize> {
+    let Database {
+        header,
+        schema_cells,
+        pager,
+        encoding,
+    } = database;
+    let (table, rootpage, _) = writable_table(schema_cells, &delete.table)?;
+    let positions = table.record_positions();
+    // The rows are found before any is deleted, as deleting reshapes the tree
+    let mut rowids = vec![];
+    for cell in page::read_table(pager, rootpage, *encoding)? {
+        let page::RecordCell { rowid, record } = cell?;
+        if let Some(filter) = &delete.filter {
+            let row = sql::TableRow {
+                table,
+                rowid: Some(rowid),
+                positions: &positions,
+                cells: &record.column.cells,
+            };
+            if sql::truth_of(&filter.evaluate(&row)?) != Some(true) {
+                continue;
+            }
+        }
+        rowids.push(rowid);
+    }
+    for rowid in &rowids {
+        delete_row(pager, header, rootpage, *rowid)?;
+    }
+    Ok(rowids.len())
+}
+/// Rewrites the rows of a table that an UPDATE statement matches, giving the number updated.
+///
+/// A row keeping its rowid is rewritten where it is, while a row whose
+/// rowid changes is deleted and inserted again under the new one.
+pub fn update<R: io::Read + io::Write + io::Seek>(
+    database: &mut Database<R>,
+    update: &SqlUpdate,
+) -> io::Result<usize> {
+    let Database {
+        header,
+        schema_cells,
+        pager,
+        encoding,
+    } = database;
+    let (table, rootpage, _) = writable_table(schema_cells, &update.table)?;
+    let positions = table.record_positions();
+    // Every new row is worked out from the rows as they were, before any is written
+    let mut changes = vec![];
+    for cell in page::read_table(pager, rootpage, *encoding)? {
+        let page::RecordCell { rowid, record } = cell?;
+        let row = sql::TableRow {
+            table,
+            rowid: Some(rowid),
+            positions: &positions,
+            cells: &record.column.cells,
+        };
+        if let Some(filter) = &update.filter {
+            if sql::truth_of(&filter.evaluate(&row)?) != Some(true) {
+                continue;
+            }
+        }
+        changes.push((rowid, updated_record(&row, &update.assignments)?));
+    }
+    for (rowid, (new_rowid, values)) in &changes {
+        let payload = record::write_record(values, *encoding)?;
+        if rowid == new_rowid {
+            insert_row(pager, header, rootpage, *rowid, &payload, Conflict::Replace)?;
+        } else {
+            delete_row(pager, header, rootpage, *rowid)?;
+            insert_row(
+                pager,
+                header,
+                rootpage,
+                *new_rowid,
+                &payload,
+                Conflict::Abort,
+            )
+            .map_err(|e| unique_constraint(table, e))?;
+        }
+    }
+    Ok(changes.len())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{self, pager};
+
+    fn open(bytes: Vec<u8>) -> Database<io::Cursor<Vec<u8>>> {
+        let header = database::read_header(&mut bytes.as_slice()).unwrap();
+        let pager = Pager::new(io::Cursor::new(bytes), pager::layout_of(&header), 16);
+        database::load(pager).unwrap()
+    }
+    fn parse_insert(sql: &str) -> SqlInsert {
+        match sql::parse(sql.bytes()) {
+            Ok(sql::Sql::Insert(insert)) => insert,
+            otherwise => panic!("expected an insert, got {otherwise:?}"),
+        }
+    }
+    fn parse_update(sql: &str) -> SqlUpdate {
+        match sql::parse(sql.bytes()) {
+            Ok(sql::Sql::Update(update)) => update,
+            otherwise => panic!("expected an update, got {otherwise:?}"),
+        }
+    }
+    fn parse_delete(sql: &str) -> SqlDelete {
+        match sql::parse(sql.bytes()) {
+            Ok(sql::Sql::Delete(delete)) => delete,
+            otherwise => panic!("expected a delete, got {otherwise:?}"),
+        }
+    }
+    #[test]
+    fn distributes_cells_evenly_and_takes_out_interior_dividers() {
+        let cells: Vec<Vec<u8>> = (0..10).map(|_| vec![0; 18]).collect();
+        // Each cell takes 20 bytes with its pointer, so 100 bytes hold five
+        let pages = distribute(&cells, BTreePageType::LeafTable, 100).unwrap();
+        assert_eq!(pages, [(0..5, Some(5)), (5..10, None)]);
+        let pages = distribute(&cells, BTreePageType::LeafTable, 80).unwrap();
+        assert_eq!(pages, [(0..4, Some(4)), (4..7, Some(7)), (7..10, None)]);
+        let pages = distribute(&cells, BTreePageType::InteriorTable, 100).unwrap();
+        assert_eq!(pages, [(0..5, Some(5)), (6..10, None)]);
+        assert!(distribute(&[vec![0; 200]], BTreePageType::LeafTable, 100).is_err());
+    }
+    #[test]
+    fn inserts_split_leaves_under_a_new_root() {
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        let rootpage = database
+            .schema_cells
+            .iter()
+            .find(|schema| schema.column.name == b"oranges")
+            .unwrap()
+            .column
+            .rootpage;
+        // Descending rowids each land in the first leaf, splitting it over and over
+        for batch in (0..40).rev() {
+            let rows: Vec<String> = (0..25)
+                .rev()
+                .map(|row| format!("({}, '{}', 'x')", 1000 + batch * 25 + row, "o".repeat(600)))
+                .collect();
+            let insert = parse_insert(&format!("insert into oranges values {}", rows.join(", ")));
+            assert_eq!(database.insert(&insert).unwrap(), 25);
+        }
+        let TablePath { ancestors, .. } = find_leaf(&mut database.pager, rootpage, 1000).unwrap();
+        assert_eq!(ancestors.len(), 1);
+        let rowids: Vec<i64> = database
+            .rows(rootpage)
+            .unwrap()
+            .map(|cell| cell.unwrap().rowid)
+            .collect();
+        assert_eq!(rowids.len(), 6 + 1000);
+        assert!(rowids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(database.integrity_check().unwrap(), ["ok"]);
+    }
+    #[test]
+    fn walking_a_tree_that_loops_is_an_error() {
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        let rootpage = database
+            .schema_cells
+            .iter()
+            .find(|schema| schema.column.name == b"oranges")
+            .unwrap()
+            .column
+            .rootpage;
+        let rows: Vec<String> = (0..20)
+            .map(|row| format!("({}, '{}', 'x')", 100 + row, "o".repeat(600)))
+            .collect();
+        let insert = parse_insert(&format!("insert into oranges values {}", rows.join(", ")));
+        database.insert(&insert).unwrap();
+        assert!(find_leaf(&mut database.pager, rootpage, 100)
+            .is_ok_and(|path| path.ancestors.len() == 1));
+        // Point the right-most child of the root back at the root
+        let mut page = database.pager.page(rootpage).unwrap().to_vec();
+        put_u32(&mut page, 8, rootpage);
+        database.pager.write_page(rootpage, page).unwrap();
+        let rows = database.rows(rootpage).unwrap();
+        assert!(rows
+            .collect::<io::Result<Vec<_>>>()
+            .is_err_and(|e| e.kind() == io::ErrorKind::InvalidData));
+    }
+    #[test]
+    fn a_commit_that_fails_part_way_is_reported_and_reread() {
+        /// A file that fails every write after the first few
+        struct FailingWrites {
+            file: io::Cursor<Vec<u8>>,
+            writes_left: usize,
+        }
+        impl io::Read for FailingWrites {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.file.read(buf)
+            }
+        }
+        impl io::Seek for FailingWrites {
+            fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+                self.file.seek(pos)
+            }
+        }
+        impl io::Write for FailingWrites {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let Some(writes_left) = self.writes_left.checked_sub(1) else {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "disk full"));
+                };
+                self.writes_left = writes_left;
+                self.file.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let bytes = std::fs::read("sample.db").unwrap();
+        let header = database::read_header(&mut bytes.as_slice()).unwrap();
+        let file = FailingWrites {
+            file: io::Cursor::new(bytes),
+            writes_left: 1,
+        };
+        let mut database = database::load(Pager::new(file, pager::layout_of(&header), 16)).unwrap();
+        let rootpage = database
+            .schema_cells
+            .iter()
+            .find(|schema| schema.column.name == b"oranges")
+            .unwrap()
+            .column
+            .rootpage;
+        let counter = database.header.file_change_counter;
+        // Page 1 is written first, then the write of the table's page fails
+        let insert = parse_insert("insert into oranges values (100, 'x', 'x')");
+        assert!(database
+            .insert(&insert)
+            .is_err_and(|e| e.to_string().contains("partly written")));
+        assert_eq!(database.header.file_change_counter, counter.wrapping_add(1));
+        let rowids: Vec<i64> = database
+            .rows(rootpage)
+            .unwrap()
+            .map(|cell| cell.unwrap().rowid)
+            .collect();
+        assert!(!rowids.contains(&100));
+    }
+    #[test]
+    fn the_lock_byte_page_is_never_allocated() {
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        let Database { pager, header, .. } = &mut database;
+        let pending_byte_page = pager::pending_byte_page(pager.layout());
+        header.in_header_database_size = pending_byte_page - 1;
+        assert_eq!(allocate_page(pager, header).unwrap(), pending_byte_page + 1);
+        assert_eq!(header.in_header_database_size, pending_byte_page + 1);
+    }
+    #[test]
+    fn freed_pages_are_allocated_again() {
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        let Database { header, pager, .. } = &mut database;
+        let page_count = header.in_header_database_size;
+        let page_idx = allocate_page(pager, header).unwrap();
+        assert_eq!(page_idx, page_count + 1);
+        free_page(pager, header, page_idx).unwrap();
+        assert_eq!(
+            (header.freelist_page_idx, header.freelist_page_count),
+            (page_idx, 1)
+        );
+        let other_page_idx = allocate_page(pager, header).unwrap();
+        free_page(pager, header, page_idx).unwrap();
+        free_page(pager, header, other_page_idx).unwrap();
+        // The second page freed is a leaf of the first, which is the trunk
+        assert_eq!(header.freelist_page_count, 2);
+        assert_eq!(allocate_page(pager, header).unwrap(), other_page_idx);
+        assert_eq!(allocate_page(pager, header).unwrap(), page_idx);
+        assert_eq!(header.freelist_page_count, 0);
+        assert_eq!(header.freelist_page_idx, 0);
+    }
+    #[test]
+    fn deletes_free_overflow_and_emptied_pages() {
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        let rows: Vec<String> = (0..60)
+            .map(|row| format!("({}, '{}', 'x')", 100 + row, "o".repeat(row * 150)))
+            .collect();
+        let insert = parse_insert(&format!("insert into oranges values {}", rows.join(", ")));
+        database.insert(&insert).unwrap();
+        let page_count = database.header.in_header_database_size;
+        assert_eq!(database.header.freelist_page_count, 0);
+        let delete = parse_delete("DELETE FROM oranges WHERE id >= 100 AND id % 10 <> 0;");
+        assert_eq!(database.delete(&delete).unwrap(), 54);
+        assert!(database.header.freelist_page_count > 0);
+        assert_eq!(database.header.in_header_database_size, page_count);
+        assert_eq!(database.integrity_check().unwrap(), ["ok"]);
+        // Freed pages are used again before the file grows
+        let deleted: Vec<&str> = rows
+            .iter()
+            .enumerate()
+            .filter(|(row, _)| row % 10 != 0)
+            .map(|(_, row)| row.as_str())
+            .collect();
+        let insert = parse_insert(&format!(
+            "insert into oranges values {}",
+            deleted.join(", ")
+        ));
+        database.insert(&insert).unwrap();
+        assert_eq!(database.header.in_header_database_size, page_count);
+        assert_eq!(database.integrity_check().unwrap(), ["ok"]);
+        assert_eq!(
+            database
+                .delete(&parse_delete("delete from oranges"))
+                .unwrap(),
+            6 + 60
+        );
+        assert_eq!(database.integrity_check().unwrap(), ["ok"]);
+    }
+    #[test]
+    fn updates_rewrite_rows_in_place_or_move_them() {
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        let rootpage = database
+            .schema_cells
+            .iter()
+            .find(|schema| schema.column.name == b"apples")
+            .unwrap()
+            .column
+            .rootpage;
+        let rows = |database: &mut Database<io::Cursor<Vec<u8>>>| -> Vec<(i64, String)> {
+            database
+                .rows(rootpage)
+                .unwrap()
+                .map(|cell| {
+                    let page::RecordCell { rowid, record } = cell.unwrap();
+                    (rowid, record.column.cells[1].to_string())
+                })
+                .collect()
+        };
+        let page_count = database.header.in_header_database_size;
+        // A name of the same length leaves the cell where it was
+        let update = parse_update("update apples set name = 'Gala' where name = 'Fuji'");
+        assert_eq!(database.update(&update).unwrap(), 1);
+        assert_eq!(rows(&mut database)[1], (2, "Gala".to_string()));
+        // Names too long for their cells move, some of them onto overflow pages
+        let long = "n".repeat(5000);
+        let update = parse_update(&format!(
+            "update apples set name = name || '{long}' where id > 2"
+        ));
+        assert_eq!(database.update(&update).unwrap(), 2);
+        assert!(database.header.in_header_database_size > page_count);
+        assert_eq!(database.integrity_check().unwrap(), ["ok"]);
+        // Changing the INTEGER PRIMARY KEY moves the row to its new rowid
+        let update = parse_update("UPDATE apples SET id = id * 10, name = 'x' WHERE id >= 3;");
+        assert_eq!(database.update(&update).unwrap(), 2);
+        let rowids: Vec<i64> = rows(&mut database)
+            .iter()
+            .map(|(rowid, _)| *rowid)
+            .collect();
+        assert_eq!(rowids, [1, 2, 30, 40]);
+        // The overflow pages of both long names are freed
+        assert_eq!(database.header.freelist_page_count, 2);
+        let update = parse_update("update apples set id = 2 where id = 1");
+        assert!(database.update(&update).is_err());
+        assert_eq!(database.integrity_check().unwrap(), ["ok"]);
+    }
+    #[test]
+    fn tables_with_triggers_or_unreadable_definitions_are_not_written() {
+        fn add_schema_entry(
+            database: &mut Database<io::Cursor<Vec<u8>>>,
+            rowid: i64,
+            [r#type, name, table, sql]: [&str; 4],
+        ) {
+            let text = |text: &str| RecordValue::EncodedString(text.as_bytes().to_vec());
+            let values = [
+                text(r#type),
+                text(name),
+                text(table),
+                RecordValue::Zero,
+                text(sql),
+            ];
+            let payload = record::write_record(&values, database.encoding).unwrap();
+            let Database { pager, header, .. } = database;
+            insert_row(pager, header, 1, rowid, &payload, Conflict::Abort).unwrap();
+            database.commit().unwrap();
+            database.schema_cells =
+                page::read_schema(&mut database.pager, database.encoding).unwrap();
+        }
+        let mut database = open(std::fs::read("sample.db").unwrap());
+        add_schema_entry(
+            &mut database,
+            100,
+            [
+                "trigger",
+                "tr",
+                "oranges",
+                "CREATE TRIGGER tr AFTER DELETE ON oranges BEGIN SELECT 1; END",
+            ],
+        );
+        let unsupported = |result: io::Result<usize>| {
+            result.is_err_and(|e| e.kind() == io::ErrorKind::Unsupported)
+        };
+        assert!(unsupported(
+            database.delete(&parse_delete("delete from oranges"))
+        ));
+        assert_eq!(
+            database
+                .delete(&parse_delete("delete from apples where id = 1"))
+                .unwrap(),
+            1
+        );
+        // The SQL of this one cannot be parsed, but it still names apples as its table
+        add_schema_entry(
+            &mut database,
+            101,
+            [
+                "index",
+                "ti",
+                "apples",
+                "CREATE INDEX ti USING apples(name)",
+            ],
+        );
+        assert!(database.schema_cells.iter().any(|schema| {
+            schema.column.name == b"ti"
+                && matches!(schema.column.object, SchemaObject::Unreadable(_))
+        }));
+        assert!(unsupported(
+            database.delete(&parse_delete("delete from apples"))
+        ));
+        // Nor can that of a virtual table, which is left alone
+        add_schema_entry(
+            &mut database,
+            102,
+            [
+                "table",
+                "pears",
+                "pears",
+                "CREATE VIRTUAL TABLE pears USING fts5(body)",
+            ],
+        );
+        assert!(unsupported(
+            database.delete(&parse_delete("delete from pears"))
+        ));
+    }
+}
@@ -3,7 +3,6 @@ use anyhow::Result;
 use std::env;
 use std::fs;
 // use std::io;
-use std::path::Path;
 
 /// A dot-command has the structure:
 ///  - It must begin with its "." at the left margin with no preceding whitespace.
@@ -14,8 +13,13 @@ pub struct Command<'a> {
     pub name: &'a str,
 }
 
-pub const COMMAND_COUNT: usize = 1;
-pub const COMMANDS: [Command<'_>; COMMAND_COUNT] = [Command { name: "dbinfo" }];
+pub const COMMAND_COUNT: usize = 4;
+pub const COMMANDS: [Command<'_>; COMMAND_COUNT] = [
+    Command { name: "dbinfo" },
+    Command { name: "tables" },
+    Command { name: "freelist" },
+    Command { name: "ptrmap" },
+];
 
 mod database;
 mod io;
@@ -31,91 +35,214 @@ fn main() -> Result<()> {
     }
     let SqliteArgs {
         database_path,
+        options,
         command,
-        remainder: _,
     } = args();
+    let database = |options| database::open_with(&database_path, options);
     match command.as_str() {
-        ".dbinfo" => db_info_command(database_path)?,
-        ".tables" => tables_command(database_path)?,
-        otherwise => sql_query_command(database_path, otherwise)?,
-        // _ => bail!("Missing or invalid command passed: {}", command),
+        ".dbinfo" => db_info_command(database(options))?,
+        ".tables" => tables_command(database(options))?,
+        ".freelist" => freelist_command(database(options))?,
+        ptrmap if ptrmap.starts_with(".ptrmap") => {
+            ptrmap_command(database(options), &ptrmap[".ptrmap".len()..])?
+        }
+        otherwise => {
+            // TODO: Proper query parsing
+            let query = sql::parse(otherwise.bytes())?;
+            let writable = query.writes();
+            sql_query_command(
+                database(database::OpenOptions {
+                    writable,
+                    ..options
+                }),
+                query,
+            )?
+        } // _ => bail!("Missing or invalid command passed: {}", command),
     }
 
     Ok(())
 }
-fn db_info_command(database_path: impl AsRef<Path>) -> io::Result<()> {
-    if let Ok(database::Database {
+fn db_info_command(database: io::Result<database::Database>) -> io::Result<()> {
+    let database::Database {
         header,
-        record_cells,
+        schema_cells,
         ..
-    }) = database::open(database_path)
-    {
-        println!("database page size: {}", header.page_size);
-        let number_of_tables = record_cells.len();
-        println!("number of tables: {number_of_tables}");
-    }
+    } = database?;
+    println!("database page size: {}", header.page_size_bytes());
+    let number_of_tables = schema_cells
+        .iter()
+        .filter(|schema| schema.column.r#type == b"table")
+        .count();
+    println!("number of tables: {number_of_tables}");
     // let database = fs::File::open(database_path).and_then(database::read)?;
     // println!("database page size: {}", database.header.page_size);
     // let number_of_tables = database.content.count();
     // println!("number of tables: {number_of_tables}");
     Ok(())
 }
-fn tables_command(database_path: impl AsRef<Path>) -> io::Result<()> {
-    if let Ok(database::Database { schema_cells, .. }) = database::open(database_path) {
-        for schema in schema_cells {
-            println!("{}", String::from_utf8_lossy(&schema.column.table_name));
+fn tables_command(database: io::Result<database::Database>) -> io::Result<()> {
+    let database::Database { schema_cells, .. } = database?;
+    for schema in schema_cells {
+        if let b"table" | b"view" = schema.column.r#type.as_slice() {
+            println!("{}", String::from_utf8_lossy(&schema.column.name));
         }
     }
     Ok(())
 }
-fn sql_query_command(database_path: impl AsRef<Path>, query: impl AsRef<str>) -> io::Result<()> {
-    // TODO: Proper query parsing
-    let query = sql::parse(query.as_ref().bytes())?;
-    if let Ok(database::Database {
-        schema_cells,
-        record_cells,
-        ..
-    }) = database::open(database_path)
-    {
-        match query {
-            sql::Sql::Select(sql::SqlSelect { query, source }) => {
-                let xs: Vec<_> = schema_cells.iter().zip(record_cells).collect();
-                eprintln!("LENGTH={}", xs.len());
-                for (schema, data) in xs {
-                    eprintln!("DATA_LENGTH={}", data.len());
-                    match schema.column.sql.signature.get(&query) {
-                        Some((term_idx, x)) => {
-                            for record in data.as_slice() {
-                                eprintln!(
-                                    "found data type {x} at index {term_idx} for signature {query}"
-                                );
-                                let Some(value) = record.column.cells.get(*term_idx).cloned()
-                                else {
-                                    eprintln!("No term at {term_idx}");
-                                    continue;
-                                };
-                                let Ok(value) = database::lift_encoded_string(value) else {
-                                    eprintln!("could not lift encoded string");
-                                    continue;
-                                };
-                                let query_result = String::from_utf8_lossy(&value);
-                                println!("{query_result}");
-                            }
+fn freelist_command(database: io::Result<database::Database>) -> io::Result<()> {
+    let mut database = database?;
+    let freelist = database.freelist()?;
+    println!("freelist trunk pages: {}", freelist.trunks.len());
+    println!("freelist leaf pages: {}", freelist.leaf_count());
+    println!("freelist page count: {}", freelist.page_count());
+    for database::freelist::FreelistTrunk { page_idx, leaves } in &freelist.trunks {
+        println!("trunk page {page_idx}: {} leaves", leaves.len());
+    }
+    if let Err(e) = freelist.verify_count(&database.header) {
+        println!("{e}");
+    }
+    Ok(())
+}
+/// Prints the type and parent of a page, as the pointer map records them
+fn ptrmap_command(database: io::Result<database::Database>, page_idx: &str) -> io::Result<()> {
+    let mut database = database?;
+    let page_idx: u32 = page_idx.trim().parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Usage: .ptrmap PAGE ({e})"),
+        )
+    })?;
+    let Some(database::PtrmapEntry { r#type, parent }) = database.parent_of(page_idx)? else {
+        println!("database has no pointer map");
+        return Ok(());
+    };
+    let r#type = match r#type {
+        database::PtrmapType::RootPage => "root page",
+        database::PtrmapType::FreePage => "free page",
+        database::PtrmapType::Overflow1 => "first overflow page",
+        database::PtrmapType::Overflow2 => "overflow page",
+        database::PtrmapType::BTree => "b-tree page",
+    };
+    println!("page {page_idx}: {}, parent {parent}", r#type);
+    Ok(())
+}
+fn sql_query_command(database: io::Result<database::Database>, query: sql::Sql) -> io::Result<()> {
+    let mut database = database?;
+    match query {
+        sql::Sql::Select(sql::SqlSelect {
+            columns,
+            source,
+            filter,
+            ..
+        }) => {
+            let Some((rootpage, table)) = database.schema_cells.iter().find_map(|schema| {
+                let table = schema.column.table()?;
+                schema
+                    .column
+                    .name
+                    .eq_ignore_ascii_case(source.as_bytes())
+                    .then(|| (schema.column.rootpage, table.clone()))
+            }) else {
+                match database
+                    .schema_cells
+                    .iter()
+                    .find_map(|schema| match &schema.column.object {
+                        database::SchemaObject::Unreadable(e)
+                            if schema.column.name.eq_ignore_ascii_case(source.as_bytes()) =>
+                        {
+                            Some(e)
                         }
-                        None => eprintln!("source {source} missing signature {query}"),
+                        _ => None,
+                    }) {
+                    Some(e) => eprintln!("cannot read the definition of {source}: {e}"),
+                    None => eprintln!("no such table: {source}"),
+                }
+                return Ok(());
+            };
+            let positions = table.record_positions();
+            let columns: Vec<sql::Expr> = columns
+                .into_iter()
+                .flat_map(|column| match column {
+                    sql::SqlResultColumn::All => table
+                        .columns()
+                        .into_iter()
+                        .map(|name| sql::Expr::Column(name.to_string()))
+                        .collect(),
+                    sql::SqlResultColumn::Expr(expr) => vec![expr],
+                })
+                .collect();
+            let rows: Box<dyn Iterator<Item = io::Result<(Option<i64>, _)>>> = if table
+                .without_rowid
+            {
+                Box::new(
+                    database
+                        .entries(rootpage)?
+                        .map(|record| record.map(|record| (None, record.column))),
+                )
+            } else {
+                Box::new(database.rows(rootpage)?.map(|cell| {
+                    cell.map(|database::RecordCell { rowid, record }| (Some(rowid), record.column))
+                }))
+            };
+            for row in rows {
+                let (rowid, column) = row?;
+                let row = sql::TableRow {
+                    table: &table,
+                    rowid,
+                    positions: &positions,
+                    cells: &column.cells,
+                };
+                if let Some(filter) = &filter {
+                    if sql::truth_of(&filter.evaluate(&row)?) != Some(true) {
+                        continue;
                     }
                 }
+                let values = columns
+                    .iter()
+                    .map(|column| column.evaluate(&row).map(|value| value.to_string()))
+                    .collect::<io::Result<Vec<_>>>()?;
+                println!("{}", values.join("|"));
+            }
+            let database::CacheStats { hits, misses } = database.pager.stats();
+            eprintln!("PAGE CACHE HITS={hits};MISSES={misses}");
+        }
+        sql::Sql::Pragma(sql::SqlPragma { name }) => match name.as_str() {
+            "integrity_check" => {
+                for line in database.integrity_check()? {
+                    println!("{line}");
+                }
             }
-            sql::Sql::CreateTable(_) => todo!("creating tables is not yet supported"),
+            otherwise => eprintln!("unsupported pragma {otherwise}"),
+        },
+        sql::Sql::Insert(insert) => {
+            let count = database.insert(&insert)?;
+            eprintln!("INSERTED {count} ROWS INTO {}", insert.table);
+        }
+        sql::Sql::Delete(delete) => {
+            let count = database.delete(&delete)?;
+            eprintln!("DELETED {count} ROWS FROM {}", delete.table);
         }
+        sql::Sql::Update(update) => {
+            let count = database.update(&update)?;
+            eprintln!("UPDATED {count} ROWS IN {}", update.table);
+        }
+        sql::Sql::CreateTable(_) => return Err(unsupported("creating tables")),
+        sql::Sql::CreateIndex(_) => return Err(unsupported("creating indexes")),
+        sql::Sql::CreateView(_) => return Err(unsupported("creating views")),
+        sql::Sql::CreateTrigger(_) => return Err(unsupported("creating triggers")),
     }
     Ok(())
 }
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{what} is not yet supported"),
+    )
+}
 struct SqliteArgs {
     database_path: String,
+    options: database::OpenOptions,
     command: String,
-    #[allow(dead_code)]
-    remainder: env::Args,
 }
 fn args() -> SqliteArgs {
     let mut args = env::args();
@@ -131,9 +258,23 @@ fn args() -> SqliteArgs {
         eprintln!("Shit args, no command {args:?}");
         panic!("Missing <command>");
     };
+    // Options trail the command, as cargo claims any leading ones
+    let mut options = database::OpenOptions::default();
+    while let Some(option) = args.next() {
+        match option.as_str() {
+            "-mmap" => {
+                let Some(size) = args.next().and_then(|size| size.parse::<u64>().ok()) else {
+                    panic!("Missing or invalid <size> for -mmap");
+                };
+                // The whole file is mapped whatever the size, zero turns mapping off
+                options.mmap = size > 0;
+            }
+            otherwise => eprintln!("Ignoring unknown option {otherwise}"),
+        }
+    }
     SqliteArgs {
         database_path,
+        options,
         command,
-        remainder: args,
     }
 }
@@ -1,29 +1,87 @@
 use std::collections::HashMap;
 
+use crate::database::RecordValue;
 use crate::io;
+
+mod expr;
+pub use expr::{apply_affinity, truth_of, Columns, Expr, NoColumns};
 #[derive(Debug)]
 pub enum Sql {
     Select(SqlSelect),
     CreateTable(SqlCreateTable),
+    CreateIndex(SqlCreateIndex),
+    CreateView(SqlCreateView),
+    CreateTrigger(SqlCreateTrigger),
+    Pragma(SqlPragma),
+    Insert(SqlInsert),
+    Delete(SqlDelete),
+    Update(SqlUpdate),
+}
+impl Sql {
+    /// Whether the statement changes the database
+    pub fn writes(&self) -> bool {
+        matches!(self, Sql::Insert(_) | Sql::Delete(_) | Sql::Update(_))
+    }
 }
 pub fn parse(data: impl IntoIterator<Item = u8>) -> io::Result<Sql> {
     let v: Vec<u8> = data.into_iter().collect();
 
-    match String::from_utf8(v).map(|s| s.to_lowercase()) {
+    match String::from_utf8(v).map(|s| lowercase_outside_literals(s.trim())) {
         Ok(s) if s.starts_with("select") => select(s).map(Sql::Select),
         Ok(s) if s.starts_with("create table") => create_table(s).map(Sql::CreateTable),
-        Ok(s) => Err(io::Error::new(std::io::ErrorKind::Unsupported, format!("Unsupported SQL: {s}"))),
-        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e))
-
+        Ok(s) if s.starts_with("create index") || s.starts_with("create unique index") => {
+            create_index(s).map(Sql::CreateIndex)
+        }
+        Ok(s) if s.starts_with("create view") => create_view(s).map(Sql::CreateView),
+        Ok(s) if s.starts_with("create trigger") => create_trigger(s).map(Sql::CreateTrigger),
+        Ok(s) if s.starts_with("pragma") => pragma(s).map(Sql::Pragma),
+        Ok(s) if s.starts_with("insert into") => insert(s).map(Sql::Insert),
+        Ok(s) if s.starts_with("delete from") => delete(s).map(Sql::Delete),
+        Ok(s) if s.starts_with("update") => update(s).map(Sql::Update),
+        Ok(s) => Err(io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Unsupported SQL: {s}"),
+        )),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
     }
 }
+/// Lowercases keywords and identifiers, but not the text of string literals
+fn lowercase_outside_literals(s: &str) -> String {
+    let mut in_literal = false;
+    s.chars()
+        .flat_map(|elt| {
+            if elt == '\'' {
+                in_literal = !in_literal;
+            }
+            let lowercase: Vec<char> = if in_literal {
+                vec![elt]
+            } else {
+                elt.to_lowercase().collect()
+            };
+            lowercase
+        })
+        .collect()
+}
+/// A column of the result of a select
+#[derive(Debug)]
+pub enum SqlResultColumn {
+    /// `*`, every column of the table in declared order
+    All,
+    Expr(Expr),
+}
 #[derive(Debug)]
 pub struct SqlSelect {
+    /// The result columns as written
+    #[allow(dead_code)]
     pub query: String,
+    pub columns: Vec<SqlResultColumn>,
     pub source: String,
+    /// The WHERE clause, which rows must satisfy
+    pub filter: Option<Expr>,
 }
 pub fn select(s: impl AsRef<str>) -> io::Result<SqlSelect> {
-    let remainder = s.as_ref()
+    let remainder = s
+        .as_ref()
         .strip_prefix("select")
         .map(str::trim)
         .ok_or(io::Error::new(
@@ -31,13 +89,31 @@ pub fn select(s: impl AsRef<str>) -> io::Result<SqlSelect> {
             "Failed to strip select prefix from select query",
         ))?;
     eprintln!("PARSING SELECT: {remainder}");
-    let (query, source) = remainder.split_once("from").ok_or(io::Error::new(
+    let remainder = remainder.trim_end_matches(';').trim_end();
+    let from = find_keyword(remainder, "from").ok_or(io::Error::new(
         io::ErrorKind::InvalidData,
         "Failed to find keyword from in select query",
     ))?;
+    let (query, source) = (remainder[..from].trim(), &remainder[from + "from".len()..]);
+    let (source, filter) = match find_keyword(source, "where") {
+        Some(idx) => (
+            &source[..idx],
+            Some(expr::parse(&source[idx + "where".len()..])?),
+        ),
+        None => (source, None),
+    };
+    let columns = split_top_level(query)
+        .map(str::trim)
+        .map(|column| match column {
+            "*" => Ok(SqlResultColumn::All),
+            column => expr::parse(column).map(SqlResultColumn::Expr),
+        })
+        .collect::<io::Result<_>>()?;
     Ok(SqlSelect {
-        query: query.trim().to_string(),
-        source: source.trim().to_string(),
+        query: query.to_string(),
+        columns,
+        source: unquote(source.trim()).to_string(),
+        filter,
     })
 }
 #[allow(dead_code)]
@@ -47,100 +123,1046 @@ unsafe fn unwrap_select(sql: Sql) -> SqlSelect {
         _ => panic!("unwrapped select"),
     }
 }
+#[allow(dead_code)]
 pub fn lift_select(sql: Sql) -> Option<SqlSelect> {
     match sql {
         Sql::Select(elt) => Some(elt),
-        _ => None
+        _ => None,
     }
 }
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SqlCreateTable {
+    #[allow(dead_code)]
     pub name: String,
     pub signature: HashMap<String, (usize, String)>,
+    /// The primary key columns, in key order, with how each is sorted
+    pub primary_key: Vec<SqlIndexedColumn>,
+    /// Whether the table is stored in an index b-tree keyed by its primary key
+    pub without_rowid: bool,
+    /// The DEFAULT value of each column that declares one, its affinity applied
+    pub defaults: HashMap<String, RecordValue>,
+}
+impl SqlCreateTable {
+    /// Where each column, in declared order, is stored in the records of the table.
+    ///
+    /// WITHOUT ROWID tables store their primary key columns first,
+    /// followed by the other columns in declared order.
+    pub fn record_positions(&self) -> Vec<usize> {
+        let declared = self.columns();
+        if !self.without_rowid {
+            return (0..declared.len()).collect();
+        }
+        let stored: Vec<&str> = self
+            .primary_key
+            .iter()
+            .map(|key| key.name.as_str())
+            .chain(
+                declared
+                    .iter()
+                    .copied()
+                    .filter(|name| !self.primary_key.iter().any(|key| key.name == *name)),
+            )
+            .collect();
+        declared
+            .iter()
+            .enumerate()
+            .map(|(term_idx, name)| {
+                stored
+                    .iter()
+                    .position(|elt| elt == name)
+                    .unwrap_or(term_idx)
+            })
+            .collect()
+    }
+    /// The column names in declared order
+    pub fn columns(&self) -> Vec<&str> {
+        let mut declared: Vec<(&String, usize)> = self
+            .signature
+            .iter()
+            .map(|(name, (term_idx, _))| (name, *term_idx))
+            .collect();
+        declared.sort_by_key(|(_, term_idx)| *term_idx);
+        declared
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+    /// The column which is another name for the rowid, if any.
+    ///
+    /// That is a sole primary key column declared with type INTEGER exactly,
+    /// in a table with rowids, unless it is declared `PRIMARY KEY DESC`.
+    pub fn rowid_alias(&self) -> Option<&str> {
+        let [SqlIndexedColumn { name, .. }] = self.primary_key.as_slice() else {
+            return None;
+        };
+        let (_, definition) = self.signature.get(name)?;
+        let is_alias = !self.without_rowid
+            && type_name(definition) == "integer"
+            && !definition.contains("primary key desc");
+        is_alias.then_some(name.as_str())
+    }
+    /// Whether the rowid alias is declared AUTOINCREMENT, so that rowids are never reused
+    pub fn autoincrement(&self) -> bool {
+        self.rowid_alias()
+            .and_then(|alias| self.signature.get(alias))
+            .is_some_and(|(_, definition)| definition.contains("autoincrement"))
+    }
+    /// The affinity of a declared column
+    pub fn affinity(&self, name: &str) -> Option<Affinity> {
+        self.signature
+            .get(name)
+            .map(|(_, definition)| Affinity::of(&type_name(definition)))
+    }
+}
+/// How values are converted when stored in, or compared with, a column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Affinity {
+    Integer,
+    Real,
+    Numeric,
+    Text,
+    Blob,
+}
+impl Affinity {
+    /// Derives the affinity of a declared type by SQLite's rules, which go by substrings
+    pub fn of(type_name: &str) -> Affinity {
+        if type_name.contains("int") {
+            Affinity::Integer
+        } else if ["char", "clob", "text"]
+            .iter()
+            .any(|elt| type_name.contains(elt))
+        {
+            Affinity::Text
+        } else if type_name.is_empty() || type_name.contains("blob") {
+            Affinity::Blob
+        } else if ["real", "floa", "doub"]
+            .iter()
+            .any(|elt| type_name.contains(elt))
+        {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+}
+/// The words a column constraint may start with, which end the type name
+const COLUMN_CONSTRAINTS: [&str; 11] = [
+    "constraint",
+    "primary",
+    "not",
+    "null",
+    "unique",
+    "check",
+    "default",
+    "collate",
+    "references",
+    "generated",
+    "as",
+];
+/// The type name of a column definition, without its constraints
+fn type_name(definition: &str) -> String {
+    definition
+        .split_whitespace()
+        .take_while(|word| !COLUMN_CONSTRAINTS.contains(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+/// Evaluates the DEFAULT clause of a column definition, if it has one.
+///
+/// The value is a literal, a signed number or a parenthesised constant expression.
+fn default_value(definition: &str) -> Option<io::Result<RecordValue>> {
+    let idx = find_keyword(definition, "default")?;
+    let rest = definition[idx + "default".len()..].trim_start();
+    let term = match split_group(rest) {
+        Some((group, _)) => group,
+        None => {
+            // Skip any sign, which may be spaced apart from the number
+            let unsigned = rest.trim_start_matches(['+', '-', ' ']);
+            let end = top_level(unsigned)
+                .find(|(_, elt)| elt.is_whitespace())
+                .map_or(unsigned.len(), |(idx, _)| idx);
+            &rest[..rest.len() - unsigned.len() + end]
+        }
+    };
+    Some(expr::parse(term).and_then(|expr| expr.evaluate(&expr::NoColumns)))
+}
+/// The names by which the rowid can be selected, unless a column is declared with them
+pub const ROWID_NAMES: [&str; 3] = ["rowid", "oid", "_rowid_"];
+/// A row of a table, whose columns expressions can refer to
+pub struct TableRow<'t> {
+    pub table: &'t SqlCreateTable,
+    /// The rowid, which WITHOUT ROWID tables lack
+    pub rowid: Option<i64>,
+    /// Where each declared column is stored in `cells`, as from `record_positions`
+    pub positions: &'t [usize],
+    /// The record, which lacks any columns added since it was written
+    pub cells: &'t [RecordValue],
+}
+impl TableRow<'_> {
+    /// Whether `name` refers to the rowid, either through its alias or a pseudo-column
+    fn is_rowid(&self, name: &str) -> bool {
+        self.rowid.is_some()
+            && (self.table.rowid_alias() == Some(name)
+                || (ROWID_NAMES.contains(&name) && !self.table.signature.contains_key(name)))
+    }
+}
+impl Columns for TableRow<'_> {
+    fn value(&self, name: &str) -> io::Result<RecordValue> {
+        if let (true, Some(rowid)) = (self.is_rowid(name), self.rowid) {
+            return Ok(RecordValue::TwosComplement64(rowid));
+        }
+        let Some((term_idx, _)) = self.table.signature.get(name) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no such column: {name}"),
+            ));
+        };
+        let value = self
+            .positions
+            .get(*term_idx)
+            .and_then(|record_idx| self.cells.get(*record_idx))
+            .or_else(|| self.table.defaults.get(name))
+            .cloned()
+            .unwrap_or(RecordValue::Null);
+        // Reals with no fractional part may be stored as integers
+        match self.table.affinity(name) {
+            Some(Affinity::Real) => Ok(expr::apply_affinity(value, Affinity::Real)),
+            _ => Ok(value),
+        }
+    }
+    fn affinity(&self, name: &str) -> Option<Affinity> {
+        if self.is_rowid(name) {
+            return Some(Affinity::Integer);
+        }
+        self.table.affinity(name)
+    }
+}
+/// The words a table constraint, rather than a column definition, may start with
+const TABLE_CONSTRAINTS: [&str; 5] = ["constraint", "primary", "unique", "check", "foreign"];
+/// Gets the columns of a `PRIMARY KEY (...)` table constraint
+fn primary_key_constraint(constraint: &str) -> Option<Vec<SqlIndexedColumn>> {
+    let constraint = match constraint.strip_prefix("constraint") {
+        // Skip the constraint name
+        Some(named) => named.trim_start().split_once(char::is_whitespace)?.1,
+        None => constraint,
+    };
+    let columns = constraint
+        .trim_start()
+        .strip_prefix("primary")?
+        .trim_start()
+        .strip_prefix("key")?
+        .trim_start();
+    let (columns, _) = split_group(columns)?;
+    Some(split_top_level(columns).map(indexed_column).collect())
 }
 fn create_table(s: impl AsRef<str>) -> io::Result<SqlCreateTable> {
-    let remainder = s.as_ref()
+    let remainder = s
+        .as_ref()
         .strip_prefix("create table")
         .map(str::trim)
         .ok_or(io::Error::new(
             io::ErrorKind::InvalidData,
             "Expected more SQL string segments",
         ))?;
-    let (name, signature_str) = remainder.split_once(' ').ok_or(io::Error::new(
+    let (name, signature_str) = remainder.split_once('(').ok_or(io::Error::new(
         io::ErrorKind::InvalidData,
         "Failed to split to name and signature group",
     ))?;
-    let name = name.to_string();
-    let signature_str = signature_str.trim_start_matches('(').trim_end_matches(')');
-    let signature_pieces = signature_str.split(',').map(str::trim);
-    let signature_pieces = signature_pieces.enumerate().map_while(|(term_idx, elt)| {
-        elt.split_once(' ')
-            .map(|(fst, snd)| (fst.to_string(), ( term_idx, snd.to_string())))
-    });
-    let signature = HashMap::from_iter(signature_pieces);
-    Ok(SqlCreateTable { name, signature })
+    let name = unquote(name.trim()).to_string();
+    let (signature_str, options) = signature_str.rsplit_once(')').ok_or(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Unterminated signature group",
+    ))?;
+    let without_rowid = options
+        .split(',')
+        .any(|option| option.split_whitespace().eq(["without", "rowid"]));
+    let mut primary_key = vec![];
+    let mut signature = HashMap::new();
+    let mut defaults = HashMap::new();
+    for elt in split_top_level(signature_str).map(str::trim) {
+        let (fst, snd) = elt.split_once(char::is_whitespace).unwrap_or((elt, ""));
+        if TABLE_CONSTRAINTS.contains(&fst) {
+            if let Some(columns) = primary_key_constraint(elt) {
+                primary_key = columns;
+            }
+            continue;
+        }
+        let name = unquote(fst).to_string();
+        let r#type = snd.trim().to_string();
+        if r#type.contains("primary key") {
+            primary_key = vec![SqlIndexedColumn {
+                name: name.clone(),
+                descending: r#type.contains("primary key desc"),
+                collation: None,
+            }];
+        }
+        match default_value(&r#type) {
+            Some(Ok(value)) => {
+                let affinity = Affinity::of(&type_name(&r#type));
+                defaults.insert(name.clone(), expr::apply_affinity(value, affinity));
+            }
+            Some(Err(e)) => eprintln!("IGNORING DEFAULT OF COLUMN {name}: {e}"),
+            None => {}
+        }
+        signature.insert(name, (signature.len(), r#type));
+    }
+    // A key column sorts by the collation it is declared with, unless the key names another
+    for column in &mut primary_key {
+        if let (None, Some((_, definition))) = (&column.collation, signature.get(&column.name)) {
+            column.collation = collation_of(definition);
+        }
+    }
+    Ok(SqlCreateTable {
+        name,
+        defaults,
+        signature,
+        primary_key,
+        without_rowid,
+    })
+}
+/// Strips one layer of SQL identifier quoting, if any
+fn unquote(s: &str) -> &str {
+    let quoted = |open, close| s.strip_prefix(open).and_then(|s| s.strip_suffix(close));
+    quoted('"', '"')
+        .or_else(|| quoted('`', '`'))
+        .or_else(|| quoted('[', ']'))
+        .unwrap_or(s)
+}
+/// The characters of `s` which are neither nested in parentheses
+/// nor quoted, with their byte offsets. An unmatched closing
+/// parenthesis is at the top level too.
+fn top_level(s: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut depth = 0usize;
+    let mut quote = None;
+    s.char_indices()
+        .filter(move |(_, elt)| match (quote, *elt) {
+            (Some(close), elt) => {
+                if elt == close {
+                    quote = None;
+                }
+                false
+            }
+            (None, '\'' | '"' | '`') => {
+                quote = Some(*elt);
+                false
+            }
+            (None, '[') => {
+                quote = Some(']');
+                false
+            }
+            (None, '(') => {
+                depth += 1;
+                false
+            }
+            (None, ')') if depth == 0 => true,
+            (None, ')') => {
+                depth -= 1;
+                false
+            }
+            (None, _) => depth == 0,
+        })
+}
+/// Splits on commas which are not nested in parentheses or quoted,
+/// so that types such as `decimal(10, 2)` stay in one piece
+fn split_top_level(s: &str) -> impl Iterator<Item = &str> {
+    let mut start = 0;
+    let mut pieces = vec![];
+    for (idx, _) in top_level(s).filter(|(_, elt)| *elt == ',') {
+        pieces.push(&s[start..idx]);
+        start = idx + 1;
+    }
+    pieces.push(&s[start..]);
+    pieces.into_iter()
+}
+/// Finds the first top-level occurrence of `keyword` as a whole word
+fn find_keyword(s: &str, keyword: &str) -> Option<usize> {
+    let is_word = |elt: char| elt.is_alphanumeric() || elt == '_';
+    top_level(s).map(|(idx, _)| idx).find(|idx| {
+        s[*idx..].starts_with(keyword)
+            && !s[..*idx].ends_with(is_word)
+            && !s[*idx + keyword.len()..].starts_with(is_word)
+    })
+}
+/// A column of an index, in the order the index sorts by
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SqlIndexedColumn {
+    /// The column name, or the whole expression for indexes on expressions
+    pub name: String,
+    pub descending: bool,
+    pub collation: Option<String>,
+}
+#[derive(Debug)]
+pub struct SqlCreateIndex {
+    #[allow(dead_code)]
+    pub name: String,
+    #[allow(dead_code)]
+    pub table: String,
+    pub columns: Vec<SqlIndexedColumn>,
+    /// Whether a WHERE clause limits the index to some of the rows
+    pub partial: bool,
+}
+/// Splits off the parenthesised group at the start of `s`,
+/// giving what is inside it and what follows it
+fn split_group(s: &str) -> Option<(&str, &str)> {
+    let s = s.strip_prefix('(')?;
+    let (idx, _) = top_level(s).find(|(_, elt)| *elt == ')')?;
+    Some((&s[..idx], &s[idx + 1..]))
+}
+/// The collation named by a `COLLATE` clause, if any
+fn collation_of(term: &str) -> Option<String> {
+    let words: Vec<&str> = term.split_whitespace().collect();
+    words
+        .iter()
+        .position(|word| *word == "collate")
+        .and_then(|idx| words.get(idx + 1))
+        .map(|collation| unquote(collation).to_string())
+}
+fn indexed_column(term: &str) -> SqlIndexedColumn {
+    let words: Vec<&str> = term.split_whitespace().collect();
+    let collation = collation_of(term);
+    let descending = words.last().is_some_and(|word| *word == "desc");
+    let name = match words
+        .iter()
+        .position(|word| ["collate", "asc", "desc"].contains(word))
+    {
+        Some(idx) => words[..idx].join(" "),
+        None => words.join(" "),
+    };
+    SqlIndexedColumn {
+        name: unquote(&name).to_string(),
+        descending,
+        collation,
+    }
+}
+fn create_index(s: impl AsRef<str>) -> io::Result<SqlCreateIndex> {
+    let remainder = s
+        .as_ref()
+        .strip_prefix("create")
+        .map(|s| s.trim_start().trim_start_matches("unique").trim_start())
+        .and_then(|s| s.strip_prefix("index"))
+        .map(|s| s.trim_start().trim_start_matches("if not exists").trim())
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected more SQL string segments",
+        ))?;
+    let (target, columns) = remainder.split_at(remainder.find('(').ok_or(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Failed to find the indexed columns",
+    ))?);
+    let on = find_keyword(target, "on").ok_or(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Failed to split to index name and table name",
+    ))?;
+    let (name, table) = (&target[..on], &target[on + "on".len()..]);
+    let (columns, rest) = split_group(columns).ok_or(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Unterminated indexed column group",
+    ))?;
+    Ok(SqlCreateIndex {
+        name: unquote(name.trim()).to_string(),
+        table: unquote(table.trim()).to_string(),
+        columns: split_top_level(columns).map(indexed_column).collect(),
+        partial: rest.trim_start().starts_with("where"),
+    })
+}
+/// Strips `if not exists` and splits off the object name that follows
+fn split_object_name(s: &str) -> Option<(String, &str)> {
+    let s = s.trim_start();
+    let s = s.strip_prefix("if not exists").unwrap_or(s).trim_start();
+    let end = match s.chars().next()? {
+        '"' | '`' | '[' => s[1..].find(['"', '`', ']']).map(|idx| idx + 2)?,
+        _ => s
+            .find(|elt: char| elt.is_whitespace() || elt == '(')
+            .unwrap_or(s.len()),
+    };
+    Some((unquote(&s[..end]).to_string(), &s[end..]))
+}
+#[derive(Debug)]
+pub struct SqlCreateView {
+    #[allow(dead_code)]
+    pub name: String,
+    /// The SELECT statement the view stands for
+    #[allow(dead_code)]
+    pub select: String,
+}
+fn create_view(s: impl AsRef<str>) -> io::Result<SqlCreateView> {
+    let (name, remainder) = s
+        .as_ref()
+        .strip_prefix("create view")
+        .and_then(split_object_name)
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a view name",
+        ))?;
+    let select = find_keyword(remainder, "as")
+        .map(|idx| &remainder[idx + "as".len()..])
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to find keyword as in create view",
+        ))?;
+    Ok(SqlCreateView {
+        name,
+        select: select.trim().to_string(),
+    })
+}
+#[derive(Debug)]
+pub struct SqlCreateTrigger {
+    #[allow(dead_code)]
+    pub name: String,
+    /// The table whose changes fire the trigger
+    #[allow(dead_code)]
+    pub table: String,
+}
+fn create_trigger(s: impl AsRef<str>) -> io::Result<SqlCreateTrigger> {
+    let (name, remainder) = s
+        .as_ref()
+        .strip_prefix("create trigger")
+        .and_then(split_object_name)
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a trigger name",
+        ))?;
+    let (table, _) = find_keyword(remainder, "on")
+        .and_then(|idx| split_object_name(&remainder[idx + "on".len()..]))
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to find the table of the trigger",
+        ))?;
+    Ok(SqlCreateTrigger { name, table })
+}
+#[derive(Debug)]
+pub struct SqlPragma {
+    pub name: String,
+}
+fn pragma(s: impl AsRef<str>) -> io::Result<SqlPragma> {
+    let name = s
+        .as_ref()
+        .strip_prefix("pragma")
+        .map(|s| s.trim().trim_end_matches(';').trim())
+        .filter(|name| !name.is_empty())
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a pragma name",
+        ))?;
+    Ok(SqlPragma {
+        name: name.to_string(),
+    })
+}
+#[derive(Debug)]
+pub struct SqlInsert {
+    pub table: String,
+    /// The columns given values, or none for every column in declared order
+    pub columns: Vec<String>,
+    /// The values of each row to insert
+    pub rows: Vec<Vec<Expr>>,
+}
+fn insert(s: impl AsRef<str>) -> io::Result<SqlInsert> {
+    let (table, remainder) = s
+        .as_ref()
+        .strip_prefix("insert into")
+        .and_then(split_object_name)
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a table name",
+        ))?;
+    let remainder = remainder.trim().trim_end_matches(';').trim_end();
+    let (columns, remainder) = match split_group(remainder) {
+        Some((columns, remainder)) => (
+            split_top_level(columns)
+                .map(|column| unquote(column.trim()).to_string())
+                .collect(),
+            remainder.trim_start(),
+        ),
+        None => (vec![], remainder),
+    };
+    let mut remainder = remainder
+        .strip_prefix("values")
+        .map(str::trim_start)
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to find keyword values in insert",
+        ))?;
+    let mut rows = vec![];
+    loop {
+        let (values, rest) = split_group(remainder).ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a parenthesised row of values",
+        ))?;
+        rows.push(
+            split_top_level(values)
+                .map(expr::parse)
+                .collect::<io::Result<_>>()?,
+        );
+        match rest.trim_start().strip_prefix(',') {
+            Some(rest) => remainder = rest.trim_start(),
+            None if rest.trim().is_empty() => break,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected {rest} after values"),
+                ))
+            }
+        }
+    }
+    Ok(SqlInsert {
+        table,
+        columns,
+        rows,
+    })
+}
+#[derive(Debug)]
+pub struct SqlDelete {
+    pub table: String,
+    /// Which rows to delete, or none for every row
+    pub filter: Option<Expr>,
+}
+fn delete(s: impl AsRef<str>) -> io::Result<SqlDelete> {
+    let s = s.as_ref().trim_end_matches(';').trim_end();
+    let (table, remainder) = s
+        .strip_prefix("delete from")
+        .and_then(split_object_name)
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a table name",
+        ))?;
+    let remainder = remainder.trim();
+    let filter = match find_keyword(remainder, "where") {
+        Some(0) => Some(expr::parse(&remainder["where".len()..])?),
+        None if remainder.is_empty() => None,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected {remainder} after table name in delete"),
+            ))
+        }
+    };
+    Ok(SqlDelete { table, filter })
+}
+#[derive(Debug)]
+pub struct SqlUpdate {
+    pub table: String,
+    /// Each column set, with the expression for its new value
+    pub assignments: Vec<(String, Expr)>,
+    /// Which rows to update, or none for every row
+    pub filter: Option<Expr>,
+}
+fn update(s: impl AsRef<str>) -> io::Result<SqlUpdate> {
+    let s = s.as_ref().trim_end_matches(';').trim_end();
+    let (table, remainder) =
+        s.strip_prefix("update")
+            .and_then(split_object_name)
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected a table name",
+            ))?;
+    let remainder = remainder
+        .trim_start()
+        .strip_prefix("set")
+        .filter(|remainder| remainder.starts_with(char::is_whitespace))
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to find keyword set in update",
+        ))?;
+    let (assignments, filter) = match find_keyword(remainder, "where") {
+        Some(idx) => (
+            &remainder[..idx],
+            Some(expr::parse(&remainder[idx + "where".len()..])?),
+        ),
+        None => (remainder, None),
+    };
+    let assignments = split_top_level(assignments)
+        .map(|assignment| {
+            let (idx, _) =
+                top_level(assignment)
+                    .find(|(_, elt)| *elt == '=')
+                    .ok_or(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Expected column = value, got {}", assignment.trim()),
+                    ))?;
+            let column = unquote(assignment[..idx].trim()).to_string();
+            Ok((column, expr::parse(&assignment[idx + 1..])?))
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(SqlUpdate {
+        table,
+        assignments,
+        filter,
+    })
 }
 #[allow(dead_code)]
 unsafe fn unwrap_create_table(sql: Sql) -> SqlCreateTable {
     match sql {
         Sql::CreateTable(elt) => elt,
-        _ => panic!("unwrapped create table")
+        _ => panic!("unwrapped create table"),
     }
 }
 pub fn lift_create_table(sql: Sql) -> Option<SqlCreateTable> {
     match sql {
         Sql::CreateTable(elt) => Some(elt),
-        _ => None
+        _ => None,
+    }
+}
+pub fn lift_create_view(sql: Sql) -> Option<SqlCreateView> {
+    match sql {
+        Sql::CreateView(elt) => Some(elt),
+        _ => None,
+    }
+}
+pub fn lift_create_trigger(sql: Sql) -> Option<SqlCreateTrigger> {
+    match sql {
+        Sql::CreateTrigger(elt) => Some(elt),
+        _ => None,
+    }
+}
+pub fn lift_create_index(sql: Sql) -> Option<SqlCreateIndex> {
+    match sql {
+        Sql::CreateIndex(elt) => Some(elt),
+        _ => None,
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const CREATE_TABLE: &[u8] = 
+    const CREATE_TABLE: &[u8] =
 b"CREATE TABLE tablename (id integer primary key, butterscotch text,strawberry text,chocolate text,pistachio text,coffee text)";
     #[test]
     fn create_table_is_ok() {
-        let table = parse(CREATE_TABLE.iter().copied()).map(|elt| unsafe {unwrap_create_table(elt)});
+        let table =
+            parse(CREATE_TABLE.iter().copied()).map(|elt| unsafe { unwrap_create_table(elt) });
         assert!(table.is_ok());
     }
     #[test]
     fn create_table_name_matches() {
-        let table = parse(CREATE_TABLE.iter().copied()).map(|elt| unsafe {unwrap_create_table(elt)});
+        let table =
+            parse(CREATE_TABLE.iter().copied()).map(|elt| unsafe { unwrap_create_table(elt) });
         assert!(table.is_ok_and(|SqlCreateTable { name, .. }| name == "tablename"))
     }
     #[test]
     fn create_table_signature_matches() {
-        let table = parse(CREATE_TABLE.iter().copied()).map(|elt| unsafe {unwrap_create_table(elt)});
-        assert!(table.is_ok_and(|SqlCreateTable {signature, ..}|
-            signature.get("id").is_some_and(|(_, id)| id == "integer primary key")
-            &&
-            signature.get("butterscotch").is_some_and(|(_, elt)| elt == "text")
-            &&
-            signature.get("strawberry").is_some_and(|(_, elt)| elt == "text")
-            &&
-            signature.get("chocolate").is_some_and(|(_, elt)| elt == "text")
-            &&
-            signature.get("pistachio").is_some_and(|(_,elt)| elt == "text")
-            &&
-            signature.get("coffee").is_some_and(|(_,elt)| elt == "text")
+        let table =
+            parse(CREATE_TABLE.iter().copied()).map(|elt| unsafe { unwrap_create_table(elt) });
+        assert!(table.is_ok_and(|SqlCreateTable { signature, .. }| signature
+            .get("id")
+            .is_some_and(|(_, id)| id == "integer primary key")
+            && signature
+                .get("butterscotch")
+                .is_some_and(|(_, elt)| elt == "text")
+            && signature
+                .get("strawberry")
+                .is_some_and(|(_, elt)| elt == "text")
+            && signature
+                .get("chocolate")
+                .is_some_and(|(_, elt)| elt == "text")
+            && signature
+                .get("pistachio")
+                .is_some_and(|(_, elt)| elt == "text")
+            && signature
+                .get("coffee")
+                .is_some_and(|(_, elt)| elt == "text")))
+    }
+    const CREATE_TABLE_MULTILINE: &[u8] =
+        b"CREATE TABLE apples\n(\n\tid integer primary key autoincrement,\n\tname text,\n\tcolor text\n)";
+    #[test]
+    fn create_table_multiline_matches() {
+        let table = parse(CREATE_TABLE_MULTILINE.iter().copied())
+            .map(|elt| unsafe { unwrap_create_table(elt) });
+        assert!(table.is_ok_and(
+            |SqlCreateTable {
+                 name, signature, ..
+             }| name == "apples"
+                && signature.get("id").is_some_and(|(idx, _)| *idx == 0)
+                && signature.get("name").is_some_and(|(idx, _)| *idx == 1)
+                && signature
+                    .get("color")
+                    .is_some_and(|(idx, elt)| *idx == 2 && elt == "text")
         ))
-        
+    }
+    #[test]
+    fn create_table_without_types_matches() {
+        let table = parse(b"CREATE TABLE sqlite_sequence(name,seq)".iter().copied())
+            .map(|elt| unsafe { unwrap_create_table(elt) });
+        assert!(table.is_ok_and(
+            |SqlCreateTable {
+                 name, signature, ..
+             }| name == "sqlite_sequence"
+                && signature.get("seq").is_some_and(|(idx, _)| *idx == 1)
+        ))
+    }
+    #[test]
+    fn create_index_matches() {
+        let index = parse(
+            b"CREATE UNIQUE INDEX idx_t ON \"t\" (color COLLATE NOCASE, name DESC) WHERE name > 1"
+                .iter()
+                .copied(),
+        )
+        .map(lift_create_index);
+        let Ok(Some(SqlCreateIndex {
+            name,
+            table,
+            columns,
+            partial,
+        })) = index
+        else {
+            panic!("expected create index, got {index:?}");
+        };
+        assert_eq!(
+            (name.as_str(), table.as_str(), partial),
+            ("idx_t", "t", true)
+        );
+        assert_eq!(
+            columns,
+            [
+                SqlIndexedColumn {
+                    name: "color".to_string(),
+                    descending: false,
+                    collation: Some("nocase".to_string())
+                },
+                SqlIndexedColumn {
+                    name: "name".to_string(),
+                    descending: true,
+                    collation: None
+                }
+            ]
+        );
+    }
+    #[test]
+    fn create_table_without_rowid_matches() {
+        let table = parse(
+            b"CREATE TABLE kv (k text COLLATE nocase, v blob, n int, CONSTRAINT pk PRIMARY KEY (n DESC, k)) WITHOUT ROWID"
+                .iter()
+                .copied(),
+        )
+        .map(|elt| unsafe { unwrap_create_table(elt) })
+        .unwrap();
+        assert!(table.without_rowid);
+        assert_eq!(
+            table.primary_key,
+            [
+                SqlIndexedColumn {
+                    name: "n".to_string(),
+                    descending: true,
+                    collation: None
+                },
+                SqlIndexedColumn {
+                    name: "k".to_string(),
+                    descending: false,
+                    collation: Some("nocase".to_string())
+                }
+            ]
+        );
+        assert_eq!(table.signature.len(), 3);
+        // Stored as n, k, v
+        assert_eq!(table.record_positions(), [1, 2, 0]);
+    }
+    #[test]
+    fn create_view_and_trigger_match() {
+        let view = parse(
+            b"CREATE VIEW IF NOT EXISTS \"red apples\" AS SELECT name FROM apples"
+                .iter()
+                .copied(),
+        )
+        .map(lift_create_view);
+        assert!(view.is_ok_and(|view| view
+            .is_some_and(|SqlCreateView { name, select }| name == "red apples"
+                && select == "select name from apples")));
+        let trigger = parse(
+            b"CREATE TRIGGER log_delete AFTER DELETE ON apples BEGIN SELECT 1; END"
+                .iter()
+                .copied(),
+        )
+        .map(lift_create_trigger);
+        assert!(trigger.is_ok_and(|trigger| trigger.is_some_and(
+            |SqlCreateTrigger { name, table }| name == "log_delete" && table == "apples"
+        )));
+    }
+    #[test]
+    fn multi_line_ddl_matches() {
+        let index = parse(b"CREATE INDEX ti\nON t(x)".iter().copied()).map(lift_create_index);
+        assert!(index.is_ok_and(|index| index
+            .is_some_and(|SqlCreateIndex { name, table, .. }| name == "ti" && table == "t")));
+        let view =
+            parse(b"CREATE VIEW v\nAS\nSELECT x FROM t".iter().copied()).map(lift_create_view);
+        assert!(view.is_ok_and(
+            |view| view
+                .is_some_and(|SqlCreateView { name, select }| name == "v"
+                    && select.trim() == "select x from t")
+        ));
+        let trigger = parse(
+            b"CREATE TRIGGER tr AFTER INSERT\n\tON\tt BEGIN SELECT 1; END"
+                .iter()
+                .copied(),
+        )
+        .map(lift_create_trigger);
+        assert!(trigger.is_ok_and(|trigger| trigger
+            .is_some_and(|SqlCreateTrigger { name, table }| name == "tr" && table == "t")));
     }
     const SELECT: &[u8] = b"SELECT butterscotch FROM pistachio";
     #[test]
     fn select_is_ok() {
-        let select = parse(SELECT.iter().copied()).map(|elt| unsafe {unwrap_select(elt)});
+        let select = parse(SELECT.iter().copied()).map(|elt| unsafe { unwrap_select(elt) });
         assert!(select.is_ok())
     }
     #[test]
     fn select_query_matches() {
-        let select = parse(SELECT.iter().copied()).map(|elt| unsafe {unwrap_select(elt)});
-        assert!(select.is_ok_and(|SqlSelect {query, ..}| query == "butterscotch"))        
+        let select = parse(SELECT.iter().copied()).map(|elt| unsafe { unwrap_select(elt) });
+        assert!(select.is_ok_and(|SqlSelect { query, .. }| query == "butterscotch"))
     }
     #[test]
     fn select_source_matches() {
-        let select = parse(SELECT.iter().copied()).map(|elt| unsafe {unwrap_select(elt)});
-        assert!(select.is_ok_and(|SqlSelect {source, ..}| source == "pistachio"))        
+        let select = parse(SELECT.iter().copied()).map(|elt| unsafe { unwrap_select(elt) });
+        assert!(select.is_ok_and(|SqlSelect { source, .. }| source == "pistachio"))
+    }
+    #[test]
+    fn select_where_keeps_literal_case() {
+        let select = parse(
+            b"SELECT id, name FROM \"apples\" WHERE color = 'Light Green' AND name <> 'From'"
+                .iter()
+                .copied(),
+        )
+        .map(lift_select)
+        .unwrap()
+        .unwrap();
+        assert_eq!(select.source, "apples");
+        assert_eq!(select.columns.len(), 2);
+        let table = parse(CREATE_TABLE_MULTILINE.iter().copied())
+            .map(|elt| unsafe { unwrap_create_table(elt) })
+            .unwrap();
+        let cells = [
+            RecordValue::Null,
+            RecordValue::EncodedString(b"Granny Smith".to_vec()),
+            RecordValue::EncodedString(b"Light Green".to_vec()),
+        ];
+        let row = TableRow {
+            table: &table,
+            rowid: Some(3),
+            positions: &table.record_positions(),
+            cells: &cells,
+        };
+        let filter = select.filter.unwrap().evaluate(&row).unwrap();
+        assert_eq!(truth_of(&filter), Some(true));
+    }
+    #[test]
+    fn integer_primary_key_reads_as_rowid() {
+        let table = parse(CREATE_TABLE_MULTILINE.iter().copied())
+            .map(|elt| unsafe { unwrap_create_table(elt) })
+            .unwrap();
+        assert_eq!(table.rowid_alias(), Some("id"));
+        let cells = [RecordValue::Null, RecordValue::One];
+        let row = TableRow {
+            table: &table,
+            rowid: Some(42),
+            positions: &table.record_positions(),
+            cells: &cells,
+        };
+        for name in ["id", "rowid", "oid", "_rowid_"] {
+            assert_eq!(row.value(name).unwrap().to_string(), "42");
+        }
+        // Missing trailing cells read as NULL
+        assert_eq!(row.value("color").unwrap().to_string(), "");
+        assert!(row.value("flavour").is_err());
+        let without_alias = parse(
+            b"CREATE TABLE t (id int primary key, rowid text)"
+                .iter()
+                .copied(),
+        )
+        .map(|elt| unsafe { unwrap_create_table(elt) })
+        .unwrap();
+        assert_eq!(without_alias.rowid_alias(), None);
+        assert_eq!(without_alias.affinity("id"), Some(Affinity::Integer));
+        assert_eq!(without_alias.affinity("rowid"), Some(Affinity::Text));
+    }
+    #[test]
+    fn added_columns_read_as_their_defaults() {
+        let table = parse(
+            b"CREATE TABLE t (a, b real DEFAULT 3, c text default -1 not null, d DEFAULT ('x' || 'y'), e)"
+                .iter()
+                .copied(),
+        )
+        .map(|elt| unsafe { unwrap_create_table(elt) })
+        .unwrap();
+        let cells = [RecordValue::One];
+        let row = TableRow {
+            table: &table,
+            rowid: Some(1),
+            positions: &table.record_positions(),
+            cells: &cells,
+        };
+        let values: Vec<String> = ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .map(|name| row.value(name).unwrap().to_string())
+            .collect();
+        assert_eq!(values, ["1", "3.0", "-1", "xy", ""]);
+    }
+    #[test]
+    fn insert_with_several_rows_matches() {
+        let Ok(Sql::Insert(SqlInsert {
+            table,
+            columns,
+            rows,
+        })) = parse(
+            b"INSERT INTO \"apples\" (name, color) VALUES ('Fuji', 'Red'), ('It''s (green)', NULL);"
+                .iter()
+                .copied(),
+        )
+        else {
+            panic!("expected an insert");
+        };
+        assert_eq!(table, "apples");
+        assert_eq!(columns, ["name", "color"]);
+        let rows: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| value.evaluate(&NoColumns).unwrap().to_string())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows, [["Fuji", "Red"], ["It's (green)", ""]]);
+        assert!(parse(b"INSERT INTO t VALUES (1), 2".iter().copied()).is_err());
+    }
+    #[test]
+    fn delete_with_and_without_where_matches() {
+        let Ok(Sql::Delete(SqlDelete { table, filter })) =
+            parse(b"DELETE FROM [apples] WHERE name = 'Fuji';".iter().copied())
+        else {
+            panic!("expected a delete");
+        };
+        assert_eq!(table, "apples");
+        assert!(filter.is_some());
+        let Ok(Sql::Delete(SqlDelete { filter, .. })) =
+            parse(b"delete from apples".iter().copied())
+        else {
+            panic!("expected a delete");
+        };
+        assert!(filter.is_none());
+        assert!(parse(b"delete from apples limit 1".iter().copied()).is_err());
+    }
+    #[test]
+    fn update_with_several_assignments_matches() {
+        let Ok(Sql::Update(SqlUpdate {
+            table,
+            assignments,
+            filter,
+        })) = parse(
+            b"UPDATE apples SET name = 'a, b = c', \"color\" = (x || '=') WHERE id = 1;"
+                .iter()
+                .copied(),
+        )
+        else {
+            panic!("expected an update");
+        };
+        assert_eq!(table, "apples");
+        let columns: Vec<&str> = assignments
+            .iter()
+            .map(|(column, _)| column.as_str())
+            .collect();
+        assert_eq!(columns, ["name", "color"]);
+        assert_eq!(
+            assignments[0].1.evaluate(&NoColumns).unwrap().to_string(),
+            "a, b = c"
+        );
+        assert!(filter.is_some());
+        assert!(parse(b"update apples name = 'x'".iter().copied()).is_err());
     }
 }
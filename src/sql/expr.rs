@@ -0,0 +1,887 @@
+use core::cmp::Ordering;
+
+use crate::database::record::{self, RecordValue};
+use crate::io;
+use crate::sql::Affinity;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+    /// A bare word, which is either a keyword or an identifier
+    Word(String),
+    /// A quoted identifier, which is never a keyword
+    Quoted(String),
+    Symbol(&'static str),
+}
+/// Symbols longest first, so that `<=` is not read as `<` then `=`
+const SYMBOLS: [&str; 17] = [
+    "||", "<=", ">=", "<>", "!=", "==", "=", "<", ">", "+", "-", "*", "/", "%", "(", ")", ",",
+];
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+/// Reads a quoted run ending in `close`, in which a doubled `close` stands for itself
+fn read_quoted(
+    chars: &mut core::iter::Peekable<core::str::Chars>,
+    close: char,
+) -> io::Result<String> {
+    let mut quoted = String::new();
+    loop {
+        match chars.next() {
+            Some(elt) if elt == close && chars.peek() == Some(&close) => {
+                chars.next();
+                quoted.push(close);
+            }
+            Some(elt) if elt == close => return Ok(quoted),
+            Some(elt) => quoted.push(elt),
+            None => return Err(invalid(format!("unterminated {close} quote"))),
+        }
+    }
+}
+fn read_number(digits: &str) -> io::Result<Token> {
+    if let Some(hex) = digits.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16)
+            .map(|value| Token::Integer(value as i64))
+            .map_err(|e| invalid(format!("malformed hex literal {digits}: {e}")));
+    }
+    match digits.parse::<i64>() {
+        Ok(value) => Ok(Token::Integer(value)),
+        // Integers too large for 64 bits are read as reals
+        Err(_) => digits
+            .parse::<f64>()
+            .map(Token::Real)
+            .map_err(|e| invalid(format!("malformed number {digits}: {e}"))),
+    }
+}
+fn tokenize(s: &str) -> io::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&elt) = chars.peek() {
+        if elt.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let rest: String = chars.clone().collect();
+        let token = match elt {
+            '\'' => {
+                chars.next();
+                Token::Text(read_quoted(&mut chars, '\'')?)
+            }
+            '"' | '`' => {
+                chars.next();
+                Token::Quoted(read_quoted(&mut chars, elt)?)
+            }
+            '[' => {
+                chars.next();
+                Token::Quoted(read_quoted(&mut chars, ']')?)
+            }
+            'x' | 'X' if rest[1..].starts_with('\'') => {
+                chars.next();
+                chars.next();
+                let hex = read_quoted(&mut chars, '\'')?;
+                let blob = (0..hex.len())
+                    .step_by(2)
+                    .map(|idx| {
+                        hex.get(idx..idx + 2)
+                            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    })
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or(invalid(format!("malformed blob literal x'{hex}'")))?;
+                Token::Blob(blob)
+            }
+            elt if elt.is_ascii_digit()
+                || (elt == '.' && rest[1..].starts_with(|elt: char| elt.is_ascii_digit())) =>
+            {
+                let mut digits = String::new();
+                while let Some(&elt) = chars.peek() {
+                    let is_exponent_sign = matches!(elt, '+' | '-')
+                        && digits.ends_with(['e', 'E'])
+                        && !digits.starts_with("0x");
+                    if elt.is_ascii_alphanumeric() || elt == '.' || is_exponent_sign {
+                        digits.push(elt);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                read_number(&digits.to_ascii_lowercase())?
+            }
+            elt if elt.is_alphabetic() || elt == '_' => {
+                let mut word = String::new();
+                while let Some(&elt) = chars.peek() {
+                    if elt.is_alphanumeric() || elt == '_' || elt == '$' {
+                        word.push(elt);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Word(word.to_lowercase())
+            }
+            _ => {
+                let symbol = SYMBOLS
+                    .iter()
+                    .find(|symbol| rest.starts_with(**symbol))
+                    .ok_or(invalid(format!("unrecognized token {elt}")))?;
+                for _ in 0..symbol.len() {
+                    chars.next();
+                }
+                Token::Symbol(symbol)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Plus,
+    Not,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Is,
+    IsNot,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+}
+/// A parsed SQL expression
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Literal(RecordValue),
+    Column(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    In {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+    },
+}
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word == keyword)
+    }
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let is_keyword = self.is_keyword(keyword);
+        if is_keyword {
+            self.position += 1;
+        }
+        is_keyword
+    }
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let is_symbol = matches!(self.peek(), Some(Token::Symbol(elt)) if *elt == symbol);
+        if is_symbol {
+            self.position += 1;
+        }
+        is_symbol
+    }
+    fn expect_symbol(&mut self, symbol: &str) -> io::Result<()> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(invalid(format!("expected {symbol} near {:?}", self.peek())))
+        }
+    }
+    fn or(&mut self) -> io::Result<Expr> {
+        let mut lhs = self.and()?;
+        while self.eat_keyword("or") {
+            lhs = Expr::Binary(BinaryOp::Or, Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+    fn and(&mut self) -> io::Result<Expr> {
+        let mut lhs = self.not()?;
+        while self.eat_keyword("and") {
+            lhs = Expr::Binary(BinaryOp::And, Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+    fn not(&mut self) -> io::Result<Expr> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Unary(UnaryOp::Not, Box::new(self.not()?)));
+        }
+        self.equality()
+    }
+    /// `=`, `<>`, IS, BETWEEN, IN and LIKE, which bind looser than `<` and the like
+    fn equality(&mut self) -> io::Result<Expr> {
+        let mut lhs = self.relational()?;
+        loop {
+            let op = match self.peek().cloned() {
+                Some(Token::Symbol("=" | "==")) => BinaryOp::Equal,
+                Some(Token::Symbol("!=" | "<>")) => BinaryOp::NotEqual,
+                Some(Token::Word(word)) => match word.as_str() {
+                    "is" => {
+                        self.position += 1;
+                        let op = if self.eat_keyword("not") {
+                            BinaryOp::IsNot
+                        } else {
+                            BinaryOp::Is
+                        };
+                        lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.relational()?));
+                        continue;
+                    }
+                    "isnull" | "notnull" => {
+                        let op = if word == "isnull" {
+                            BinaryOp::Is
+                        } else {
+                            BinaryOp::IsNot
+                        };
+                        self.position += 1;
+                        lhs = Expr::Binary(
+                            op,
+                            Box::new(lhs),
+                            Box::new(Expr::Literal(RecordValue::Null)),
+                        );
+                        continue;
+                    }
+                    "not" | "between" | "in" | "like" => {
+                        let negated = self.eat_keyword("not");
+                        if negated && self.eat_keyword("null") {
+                            lhs = Expr::Binary(
+                                BinaryOp::IsNot,
+                                Box::new(lhs),
+                                Box::new(Expr::Literal(RecordValue::Null)),
+                            );
+                            continue;
+                        }
+                        lhs = self.postfix(lhs, negated)?;
+                        continue;
+                    }
+                    _ => return Ok(lhs),
+                },
+                _ => return Ok(lhs),
+            };
+            self.position += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.relational()?));
+        }
+    }
+    fn relational(&mut self) -> io::Result<Expr> {
+        let mut lhs = self.additive()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("<")) => BinaryOp::Less,
+                Some(Token::Symbol("<=")) => BinaryOp::LessEqual,
+                Some(Token::Symbol(">")) => BinaryOp::Greater,
+                Some(Token::Symbol(">=")) => BinaryOp::GreaterEqual,
+                _ => return Ok(lhs),
+            };
+            self.position += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.additive()?));
+        }
+    }
+    /// Parses what follows an operand and an optional NOT: BETWEEN, IN or LIKE
+    fn postfix(&mut self, lhs: Expr, negated: bool) -> io::Result<Expr> {
+        let expr = Box::new(lhs);
+        if self.eat_keyword("between") {
+            let low = Box::new(self.relational()?);
+            if !self.eat_keyword("and") {
+                return Err(invalid("expected and in between"));
+            }
+            let high = Box::new(self.relational()?);
+            return Ok(Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            });
+        }
+        if self.eat_keyword("in") {
+            self.expect_symbol("(")?;
+            let mut list = vec![];
+            if !self.eat_symbol(")") {
+                loop {
+                    list.push(self.or()?);
+                    if self.eat_symbol(")") {
+                        break;
+                    }
+                    self.expect_symbol(",")?;
+                }
+            }
+            return Ok(Expr::In {
+                expr,
+                list,
+                negated,
+            });
+        }
+        if self.eat_keyword("like") {
+            let pattern = Box::new(self.relational()?);
+            return Ok(Expr::Like {
+                expr,
+                pattern,
+                negated,
+            });
+        }
+        Err(invalid(format!(
+            "expected between, in or like near {:?}",
+            self.peek()
+        )))
+    }
+    fn additive(&mut self) -> io::Result<Expr> {
+        let mut lhs = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinaryOp::Add,
+                Some(Token::Symbol("-")) => BinaryOp::Subtract,
+                _ => return Ok(lhs),
+            };
+            self.position += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.multiplicative()?));
+        }
+    }
+    fn multiplicative(&mut self) -> io::Result<Expr> {
+        let mut lhs = self.concat()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinaryOp::Multiply,
+                Some(Token::Symbol("/")) => BinaryOp::Divide,
+                Some(Token::Symbol("%")) => BinaryOp::Remainder,
+                _ => return Ok(lhs),
+            };
+            self.position += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.concat()?));
+        }
+    }
+    /// `||` binds tighter than any other binary operator
+    fn concat(&mut self) -> io::Result<Expr> {
+        let mut lhs = self.unary()?;
+        while self.eat_symbol("||") {
+            lhs = Expr::Binary(BinaryOp::Concat, Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+    fn unary(&mut self) -> io::Result<Expr> {
+        if self.eat_symbol("-") {
+            return Ok(match self.unary()? {
+                // Keep negative literals literal, so they round-trip as written
+                Expr::Literal(RecordValue::TwosComplement64(value)) => {
+                    Expr::Literal(RecordValue::TwosComplement64(value.wrapping_neg()))
+                }
+                Expr::Literal(RecordValue::Float64(value)) => {
+                    Expr::Literal(RecordValue::Float64(-value))
+                }
+                operand => Expr::Unary(UnaryOp::Negate, Box::new(operand)),
+            });
+        }
+        if self.eat_symbol("+") {
+            return Ok(Expr::Unary(UnaryOp::Plus, Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+    fn primary(&mut self) -> io::Result<Expr> {
+        match self.next() {
+            Some(Token::Integer(value)) => Ok(Expr::Literal(RecordValue::TwosComplement64(value))),
+            Some(Token::Real(value)) => Ok(Expr::Literal(RecordValue::Float64(value))),
+            Some(Token::Text(text)) => {
+                Ok(Expr::Literal(RecordValue::EncodedString(text.into_bytes())))
+            }
+            Some(Token::Blob(blob)) => Ok(Expr::Literal(RecordValue::Blob(blob))),
+            Some(Token::Word(word)) if word == "null" => Ok(Expr::Literal(RecordValue::Null)),
+            Some(Token::Word(word)) if word == "true" => Ok(Expr::Literal(integer(1))),
+            Some(Token::Word(word)) if word == "false" => Ok(Expr::Literal(integer(0))),
+            Some(Token::Word(word)) | Some(Token::Quoted(word)) => {
+                if self.eat_symbol("(") {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("no such function: {word}"),
+                    ));
+                }
+                Ok(Expr::Column(word))
+            }
+            Some(Token::Symbol("(")) => {
+                let expr = self.or()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            otherwise => Err(invalid(format!(
+                "expected an expression, found {otherwise:?}"
+            ))),
+        }
+    }
+}
+/// Parses a whole string as one expression
+pub fn parse(s: &str) -> io::Result<Expr> {
+    let mut parser = Parser {
+        tokens: tokenize(s)?,
+        position: 0,
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(invalid(format!("unexpected {token:?} after expression"))),
+    }
+}
+/// Where the column references of an expression get their values
+pub trait Columns {
+    fn value(&self, name: &str) -> io::Result<RecordValue>;
+    /// The affinity of a column, which comparisons convert the other operand to
+    fn affinity(&self, name: &str) -> Option<Affinity>;
+}
+/// No columns at all, for expressions that stand alone such as defaults
+pub struct NoColumns;
+impl Columns for NoColumns {
+    fn value(&self, name: &str) -> io::Result<RecordValue> {
+        Err(invalid(format!("no such column: {name}")))
+    }
+    fn affinity(&self, _name: &str) -> Option<Affinity> {
+        None
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Number {
+    Integer(i64),
+    Real(f64),
+}
+fn integer(value: i64) -> RecordValue {
+    RecordValue::TwosComplement64(value)
+}
+fn boolean(value: Option<bool>) -> RecordValue {
+    value.map_or(RecordValue::Null, |value| integer(value.into()))
+}
+/// The longest prefix of `text` that reads as a number, the way SQLite
+/// converts text in arithmetic, or zero when there is none
+fn numeric_prefix(text: &str) -> Number {
+    let text = text.trim_start();
+    let mut end = 0;
+    let mut is_real = false;
+    let bytes = text.as_bytes();
+    if matches!(bytes.first(), Some(b'+' | b'-')) {
+        end += 1;
+    }
+    let digits_start = end;
+    while bytes.get(end).is_some_and(u8::is_ascii_digit) {
+        end += 1;
+    }
+    if bytes.get(end) == Some(&b'.') {
+        is_real = true;
+        end += 1;
+        while bytes.get(end).is_some_and(u8::is_ascii_digit) {
+            end += 1;
+        }
+    }
+    if end == digits_start || (is_real && end == digits_start + 1) {
+        return Number::Integer(0);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let mut exponent_end = end + 1;
+        if matches!(bytes.get(exponent_end), Some(b'+' | b'-')) {
+            exponent_end += 1;
+        }
+        if bytes.get(exponent_end).is_some_and(u8::is_ascii_digit) {
+            is_real = true;
+            end = exponent_end;
+            while bytes.get(end).is_some_and(u8::is_ascii_digit) {
+                end += 1;
+            }
+        }
+    }
+    let prefix = &text[..end];
+    match (is_real, prefix.parse::<i64>()) {
+        (false, Ok(value)) => Number::Integer(value),
+        _ => Number::Real(prefix.parse().unwrap_or_default()),
+    }
+}
+/// The numeric value of a value in arithmetic, None for NULL
+fn number_of(value: &RecordValue) -> Option<Number> {
+    match value {
+        RecordValue::Null => None,
+        RecordValue::Float64(value) => Some(Number::Real(*value)),
+        RecordValue::EncodedString(text) => Some(numeric_prefix(&String::from_utf8_lossy(text))),
+        RecordValue::Blob(blob) => Some(numeric_prefix(&String::from_utf8_lossy(blob))),
+        integer => record::lift_integer(integer.clone())
+            .ok()
+            .map(Number::Integer),
+    }
+}
+fn from_number(number: Number) -> RecordValue {
+    match number {
+        Number::Integer(value) => integer(value),
+        Number::Real(value) => RecordValue::Float64(value),
+    }
+}
+/// Whether a value counts as true in a condition, None for NULL
+pub fn truth_of(value: &RecordValue) -> Option<bool> {
+    number_of(value).map(|number| match number {
+        Number::Integer(value) => value != 0,
+        Number::Real(value) => value != 0.0,
+    })
+}
+/// Reads text which is entirely a number, surrounding spaces aside
+fn parse_numeric(text: &[u8]) -> Option<Number> {
+    let text = core::str::from_utf8(text).ok()?.trim();
+    let is_numeric = |elt: char| elt.is_ascii_digit() || matches!(elt, '.' | 'e' | 'E' | '+' | '-');
+    if text.is_empty() || !text.chars().all(is_numeric) {
+        return None;
+    }
+    match text.parse::<i64>() {
+        Ok(value) => Some(Number::Integer(value)),
+        Err(_) => text.parse::<f64>().ok().map(Number::Real),
+    }
+}
+/// Converts values to numbers where that loses nothing, as columns
+/// with INTEGER, REAL or NUMERIC affinity do
+pub fn apply_numeric_affinity(value: RecordValue, affinity: Affinity) -> RecordValue {
+    let number = match &value {
+        RecordValue::Null | RecordValue::Blob(_) => return value,
+        RecordValue::EncodedString(text) => match parse_numeric(text) {
+            Some(number) => number,
+            None => return value,
+        },
+        RecordValue::Float64(real) => Number::Real(*real),
+        integer => match record::lift_integer(integer.clone()) {
+            Ok(integer) => Number::Integer(integer),
+            Err(_) => return value,
+        },
+    };
+    match (affinity, number) {
+        (Affinity::Real, Number::Integer(value)) => RecordValue::Float64(value as f64),
+        (Affinity::Integer | Affinity::Numeric, Number::Real(real))
+            if real.fract() == 0.0 && real.abs() < 9.2e18 =>
+        {
+            integer(real as i64)
+        }
+        (_, number) => from_number(number),
+    }
+}
+/// Converts a value the way storing it in a column of the given affinity does
+pub fn apply_affinity(value: RecordValue, affinity: Affinity) -> RecordValue {
+    match affinity {
+        Affinity::Integer | Affinity::Real | Affinity::Numeric => {
+            apply_numeric_affinity(value, affinity)
+        }
+        Affinity::Text => apply_text_affinity(value),
+        Affinity::Blob => value,
+    }
+}
+/// Converts numbers to text, as columns with TEXT affinity do
+pub fn apply_text_affinity(value: RecordValue) -> RecordValue {
+    match value {
+        RecordValue::Null | RecordValue::EncodedString(_) | RecordValue::Blob(_) => value,
+        number => RecordValue::EncodedString(number.to_string().into_bytes()),
+    }
+}
+/// Applies the conversions SQLite makes before comparing two operands
+fn compare_with_affinity(
+    (lhs, lhs_affinity): (RecordValue, Option<Affinity>),
+    (rhs, rhs_affinity): (RecordValue, Option<Affinity>),
+) -> Option<Ordering> {
+    let is_numeric = |affinity: Option<Affinity>| {
+        matches!(
+            affinity,
+            Some(Affinity::Integer | Affinity::Real | Affinity::Numeric)
+        )
+    };
+    let is_text_or_none = |affinity: Option<Affinity>| {
+        matches!(affinity, None | Some(Affinity::Text | Affinity::Blob))
+    };
+    let (lhs, rhs) = match (lhs_affinity, rhs_affinity) {
+        (lhs_affinity, rhs_affinity)
+            if is_numeric(lhs_affinity) && is_text_or_none(rhs_affinity) =>
+        {
+            (lhs, apply_numeric_affinity(rhs, Affinity::Numeric))
+        }
+        (lhs_affinity, rhs_affinity)
+            if is_text_or_none(lhs_affinity) && is_numeric(rhs_affinity) =>
+        {
+            (apply_numeric_affinity(lhs, Affinity::Numeric), rhs)
+        }
+        (Some(Affinity::Text), None) => (lhs, apply_text_affinity(rhs)),
+        (None, Some(Affinity::Text)) => (apply_text_affinity(lhs), rhs),
+        _ => (lhs, rhs),
+    };
+    if matches!(lhs, RecordValue::Null) || matches!(rhs, RecordValue::Null) {
+        return None;
+    }
+    Some(record::compare(&lhs, &rhs))
+}
+/// Matches `text` against a LIKE pattern, ignoring ASCII case.
+///
+/// Only the last `%` is ever backtracked to, letting it swallow one more byte,
+/// as whatever an earlier `%` matched cannot help a later one match.
+fn like(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut pattern_idx, mut text_idx) = (0, 0);
+    // The pattern past the last `%`, and how much of the text it has swallowed
+    let mut backtrack = None;
+    while text_idx < text.len() {
+        match pattern.get(pattern_idx) {
+            Some(b'%') => {
+                pattern_idx += 1;
+                backtrack = Some((pattern_idx, text_idx));
+            }
+            Some(elt) if *elt == b'_' || elt.eq_ignore_ascii_case(&text[text_idx]) => {
+                pattern_idx += 1;
+                text_idx += 1;
+            }
+            _ => {
+                let Some((after, swallowed)) = backtrack else {
+                    return false;
+                };
+                backtrack = Some((after, swallowed + 1));
+                (pattern_idx, text_idx) = (after, swallowed + 1);
+            }
+        }
+    }
+    pattern[pattern_idx..].iter().all(|elt| *elt == b'%')
+}
+fn arithmetic(op: BinaryOp, lhs: Number, rhs: Number) -> RecordValue {
+    let real = |number: Number| match number {
+        Number::Integer(value) => value as f64,
+        Number::Real(value) => value,
+    };
+    if let (Number::Integer(lhs), Number::Integer(rhs)) = (lhs, rhs) {
+        let checked = match op {
+            BinaryOp::Add => lhs.checked_add(rhs),
+            BinaryOp::Subtract => lhs.checked_sub(rhs),
+            BinaryOp::Multiply => lhs.checked_mul(rhs),
+            BinaryOp::Divide if rhs == 0 => return RecordValue::Null,
+            BinaryOp::Divide => lhs.checked_div(rhs),
+            BinaryOp::Remainder if rhs == 0 => return RecordValue::Null,
+            BinaryOp::Remainder => Some(lhs.wrapping_rem(rhs)),
+            _ => unreachable!("{op:?} is not arithmetic"),
+        };
+        // Integers overflow into reals
+        if let Some(value) = checked {
+            return integer(value);
+        }
+    }
+    let (lhs, rhs) = (real(lhs), real(rhs));
+    let value = match op {
+        BinaryOp::Add => lhs + rhs,
+        BinaryOp::Subtract => lhs - rhs,
+        BinaryOp::Multiply => lhs * rhs,
+        BinaryOp::Divide if rhs == 0.0 => return RecordValue::Null,
+        BinaryOp::Divide => lhs / rhs,
+        BinaryOp::Remainder if rhs as i64 == 0 => return RecordValue::Null,
+        BinaryOp::Remainder => ((lhs as i64) % (rhs as i64)) as f64,
+        _ => unreachable!("{op:?} is not arithmetic"),
+    };
+    RecordValue::Float64(value)
+}
+impl Expr {
+    /// The affinity the expression carries into comparisons, which only column references have
+    fn affinity(&self, columns: &dyn Columns) -> Option<Affinity> {
+        match self {
+            Expr::Column(name) => columns.affinity(name),
+            _ => None,
+        }
+    }
+    fn compare(&self, other: &Expr, columns: &dyn Columns) -> io::Result<Option<Ordering>> {
+        Ok(compare_with_affinity(
+            (self.evaluate(columns)?, self.affinity(columns)),
+            (other.evaluate(columns)?, other.affinity(columns)),
+        ))
+    }
+    /// Evaluates the expression with SQLite's semantics, NULLs included
+    pub fn evaluate(&self, columns: &dyn Columns) -> io::Result<RecordValue> {
+        let value = match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Column(name) => columns.value(name)?,
+            Expr::Unary(UnaryOp::Plus, operand) => operand.evaluate(columns)?,
+            Expr::Unary(UnaryOp::Negate, operand) => match number_of(&operand.evaluate(columns)?) {
+                None => RecordValue::Null,
+                Some(Number::Integer(value)) => value
+                    .checked_neg()
+                    .map_or(RecordValue::Float64(-(value as f64)), integer),
+                Some(Number::Real(value)) => RecordValue::Float64(-value),
+            },
+            Expr::Unary(UnaryOp::Not, operand) => {
+                boolean(truth_of(&operand.evaluate(columns)?).map(|value| !value))
+            }
+            Expr::Binary(BinaryOp::And, lhs, rhs) => match truth_of(&lhs.evaluate(columns)?) {
+                Some(false) => boolean(Some(false)),
+                lhs => match (lhs, truth_of(&rhs.evaluate(columns)?)) {
+                    (_, Some(false)) => boolean(Some(false)),
+                    (Some(true), Some(true)) => boolean(Some(true)),
+                    _ => RecordValue::Null,
+                },
+            },
+            Expr::Binary(BinaryOp::Or, lhs, rhs) => match truth_of(&lhs.evaluate(columns)?) {
+                Some(true) => boolean(Some(true)),
+                lhs => match (lhs, truth_of(&rhs.evaluate(columns)?)) {
+                    (_, Some(true)) => boolean(Some(true)),
+                    (Some(false), Some(false)) => boolean(Some(false)),
+                    _ => RecordValue::Null,
+                },
+            },
+            Expr::Binary(op @ (BinaryOp::Is | BinaryOp::IsNot), lhs, rhs) => {
+                let (lhs_value, rhs_value) = (lhs.evaluate(columns)?, rhs.evaluate(columns)?);
+                let is = match (&lhs_value, &rhs_value) {
+                    (RecordValue::Null, RecordValue::Null) => true,
+                    (RecordValue::Null, _) | (_, RecordValue::Null) => false,
+                    _ => lhs.compare(rhs, columns)? == Some(Ordering::Equal),
+                };
+                boolean(Some(is == (*op == BinaryOp::Is)))
+            }
+            Expr::Binary(
+                op @ (BinaryOp::Equal
+                | BinaryOp::NotEqual
+                | BinaryOp::Less
+                | BinaryOp::LessEqual
+                | BinaryOp::Greater
+                | BinaryOp::GreaterEqual),
+                lhs,
+                rhs,
+            ) => boolean(lhs.compare(rhs, columns)?.map(|ordering| match op {
+                BinaryOp::Equal => ordering.is_eq(),
+                BinaryOp::NotEqual => ordering.is_ne(),
+                BinaryOp::Less => ordering.is_lt(),
+                BinaryOp::LessEqual => ordering.is_le(),
+                BinaryOp::Greater => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })),
+            Expr::Binary(BinaryOp::Concat, lhs, rhs) => {
+                match (lhs.evaluate(columns)?, rhs.evaluate(columns)?) {
+                    (RecordValue::Null, _) | (_, RecordValue::Null) => RecordValue::Null,
+                    (lhs, rhs) => RecordValue::EncodedString(format!("{lhs}{rhs}").into_bytes()),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                match (
+                    number_of(&lhs.evaluate(columns)?),
+                    number_of(&rhs.evaluate(columns)?),
+                ) {
+                    (Some(lhs), Some(rhs)) => arithmetic(*op, lhs, rhs),
+                    _ => RecordValue::Null,
+                }
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                let above = expr.compare(low, columns)?.map(Ordering::is_ge);
+                let below = expr.compare(high, columns)?.map(Ordering::is_le);
+                let between = match (above, below) {
+                    (Some(false), _) | (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                };
+                boolean(between.map(|between| between != *negated))
+            }
+            Expr::In {
+                expr,
+                list,
+                negated,
+            } => {
+                let mut found = Some(false);
+                for elt in list {
+                    match expr.compare(elt, columns)? {
+                        Some(Ordering::Equal) => {
+                            found = Some(true);
+                            break;
+                        }
+                        None => found = None,
+                        Some(_) => {}
+                    }
+                }
+                boolean(found.map(|found| found != *negated))
+            }
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => match (expr.evaluate(columns)?, pattern.evaluate(columns)?) {
+                (RecordValue::Null, _) | (_, RecordValue::Null) => RecordValue::Null,
+                (text, pattern) => boolean(Some(
+                    like(pattern.to_string().as_bytes(), text.to_string().as_bytes()) != *negated,
+                )),
+            },
+        };
+        Ok(value)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(s: &str) -> String {
+        parse(s)
+            .and_then(|expr| expr.evaluate(&NoColumns))
+            .unwrap()
+            .to_string()
+    }
+    #[test]
+    fn evaluates_arithmetic_with_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3 - -4"), "11");
+        assert_eq!(evaluate("(1 + 2) * 3"), "9");
+        assert_eq!(evaluate("7 / 2"), "3");
+        assert_eq!(evaluate("7 / 2.0"), "3.5");
+        assert_eq!(evaluate("7 % 0"), "");
+        assert_eq!(evaluate("'3' + 4"), "7");
+        assert_eq!(evaluate("'a' || 1 || NULL"), "");
+        assert_eq!(evaluate("'it''s' || ' ' || x'41'"), "it's A");
+        assert_eq!(evaluate("2 * 3 || 4"), "68");
+        assert_eq!(evaluate("1 + 2 || 3"), "24");
+    }
+    #[test]
+    fn evaluates_conditions_with_three_valued_logic() {
+        assert_eq!(evaluate("1 < 2 AND 'b' > 'a'"), "1");
+        assert_eq!(evaluate("NULL = NULL"), "");
+        assert_eq!(evaluate("NULL IS NULL"), "1");
+        assert_eq!(evaluate("1 IS NOT NULL"), "1");
+        assert_eq!(evaluate("NULL AND 0"), "0");
+        assert_eq!(evaluate("NULL OR 1"), "1");
+        assert_eq!(evaluate("NOT NULL"), "");
+        assert_eq!(evaluate("3 BETWEEN 1 AND 5"), "1");
+        assert_eq!(evaluate("3 NOT IN (1, 2, 3)"), "0");
+        assert_eq!(evaluate("4 IN (1, NULL)"), "");
+        assert_eq!(evaluate("'Granny Smith' LIKE 'granny%'"), "1");
+        assert_eq!(evaluate("'Fuji' LIKE 'F_j_'"), "1");
+        assert_eq!(evaluate("'Fuji' LIKE '%j'"), "0");
+        assert_eq!(evaluate("'abcabd' LIKE '%ab_'"), "1");
+        assert_eq!(evaluate("'' LIKE '%%'"), "1");
+        // Failing patterns with many wildcards fail in linear time
+        let text = "a".repeat(40) + "b";
+        let pattern = "%a".repeat(9) + "%c";
+        assert_eq!(evaluate(&format!("'{text}' LIKE '{pattern}'")), "0");
+        // The relational operators bind tighter than equality
+        assert_eq!(evaluate("0 = 1 < 2"), "0");
+        assert_eq!(evaluate("1 < 2 = 1"), "1");
+        assert_eq!(evaluate("1 = 1 IS 1"), "1");
+    }
+    #[test]
+    fn rejects_trailing_tokens() {
+        assert!(parse("1 2").is_err());
+        assert!(parse("count(*)").is_err());
+    }
+}
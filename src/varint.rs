@@ -26,12 +26,15 @@ impl IntoIterator for Varint {
         once(a0).chain(tail)
     }
 }
+#[allow(dead_code)]
 pub fn len(Varint { tail, .. }: &Varint) -> usize {
     tail.len() + 1
 }
 fn high_bit_is_set(val: &u8) -> bool {
     val & 0b1000_0000 != 0
 }
+/// The most bytes a [`Varint`] occupies
+pub const MAX_LEN: usize = 9;
 pub fn read<R: io::Read>(r: &mut R) -> io::Result<Varint> {
     let a0 = io::read_one(r)?;
 
@@ -48,21 +51,62 @@ pub fn read<R: io::Read>(r: &mut R) -> io::Result<Varint> {
 
         Some(next)
     })
+    // The ninth byte ends the varint whatever its high bit
+    .take(MAX_LEN - 1)
     .collect();
 
     Ok(Varint { a0, tail })
 }
+/// Decodes a [`Varint`].
+///
+/// Each of the first eight bytes contributes its low seven bits,
+/// while a ninth byte contributes all eight.
 pub fn value_of(varint: &Varint) -> u64 {
-    let count = len(varint);
-    varint.into_iter().enumerate().fold(0, |acc, (idx, elt)| {
-        let elt = if idx < count - 1 {
-            *elt ^ 0b1000_0000
-        } else {
-            *elt
-        } as u64;
-        let elt = elt << (7 * (count - idx - 1));
-        elt | acc
-    })
+    varint
+        .into_iter()
+        .enumerate()
+        .fold(0, |acc, (idx, elt)| match idx {
+            idx if idx < MAX_LEN - 1 => (acc << 7) | (*elt & 0b0111_1111) as u64,
+            _ => (acc << 8) | *elt as u64,
+        })
+}
+/// Decodes a [`Varint`] as the twos-complement integer it holds, as rowids are
+pub fn signed_value_of(varint: &Varint) -> i64 {
+    value_of(varint) as i64
+}
+/// Encodes `value` in the fewest bytes possible
+pub fn encode(value: u64) -> Varint {
+    let mut bytes = vec![];
+    let mut remainder = value;
+    if value >> 56 != 0 {
+        // Needs all nine bytes, the last of which keeps eight bits
+        bytes.push(remainder as u8);
+        remainder >>= 8;
+        for _ in 0..MAX_LEN - 1 {
+            bytes.push((remainder as u8 & 0b0111_1111) | 0b1000_0000);
+            remainder >>= 7;
+        }
+    } else {
+        bytes.push(remainder as u8 & 0b0111_1111);
+        remainder >>= 7;
+        while remainder != 0 {
+            bytes.push((remainder as u8 & 0b0111_1111) | 0b1000_0000);
+            remainder >>= 7;
+        }
+    }
+    bytes.reverse();
+    let a0 = bytes.remove(0);
+    Varint { a0, tail: bytes }
+}
+impl From<u64> for Varint {
+    fn from(value: u64) -> Self {
+        encode(value)
+    }
+}
+impl From<i64> for Varint {
+    fn from(value: i64) -> Self {
+        encode(value as u64)
+    }
 }
 impl From<Varint> for u64 {
     fn from(value: Varint) -> Self {
@@ -77,3 +121,54 @@ impl From<&Varint> for u64 {
 pub fn size_of(Varint { a0, tail }: &Varint) -> usize {
     core::mem::size_of_val(a0) + tail.len()
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDGES: [u64; 14] = [
+        0,
+        1,
+        0x7F,
+        0x80,
+        0x3FFF,
+        0x4000,
+        0x1F_FFFF,
+        0x20_0000,
+        0xFF_FFFF_FFFF_FFFF,
+        0x100_0000_0000_0000,
+        i64::MAX as u64,
+        i64::MIN as u64,
+        u64::MAX - 1,
+        u64::MAX,
+    ];
+    #[test]
+    fn encoding_round_trips() {
+        for value in EDGES {
+            let bytes: Vec<u8> = encode(value).into_iter().collect();
+            let decoded = read(&mut bytes.as_slice()).map(|varint| value_of(&varint));
+            assert!(
+                decoded.as_ref().is_ok_and(|decoded| *decoded == value),
+                "{value:#X} decoded as {decoded:?}"
+            );
+        }
+    }
+    #[test]
+    fn encoding_is_shortest() {
+        let lengths = EDGES.map(|value| len(&encode(value)));
+        assert_eq!(lengths, [1, 1, 1, 2, 2, 3, 3, 4, 8, 9, 9, 9, 9, 9]);
+    }
+    #[test]
+    fn signed_values_round_trip() {
+        for value in [0, 1, -1, i64::MIN, i64::MAX, -129, 4096] {
+            assert_eq!(signed_value_of(&Varint::from(value)), value);
+        }
+        assert_eq!(len(&Varint::from(-1i64)), MAX_LEN);
+    }
+    #[test]
+    fn ninth_byte_carries_eight_bits() {
+        let bytes = [0xFF; MAX_LEN + 1];
+        let varint = read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(len(&varint), MAX_LEN);
+        assert_eq!(value_of(&varint), u64::MAX);
+    }
+}